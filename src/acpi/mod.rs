@@ -0,0 +1,248 @@
+mod tables;
+
+pub use tables::{
+    Fadt, GenericAddress, Hpet, InterruptOverride, IoApic, Madt, Mcfg, PciSegment, Processor,
+};
+
+use crate::{mem::paging::PHYS_OFFSET, sync::Once};
+use core::{ptr, slice};
+use x86_64::PhysAddr;
+
+pub static ACPI: Once<Acpi> = Once::new();
+
+#[derive(Debug)]
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    /// Values to write to `SLP_TYPa` and `SLP_TYPb` to enter the S5 soft-off state
+    pub fn s5_sleep_type(&self) -> Option<(u8, u8)> {
+        let dsdt = Sdt::at(self.fadt?.dsdt)?;
+        let aml = unsafe { slice::from_raw_parts(virt(dsdt.data()), dsdt.data_len()) };
+        find_s5(aml)
+    }
+}
+
+/// Looks for the firmware tables, leaving `ACPI` uninitialised if there are none
+pub fn init() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return,
+    };
+
+    let revision: u8 = unsafe { read(rsdp + 15u64) };
+    let oem_id: [u8; 6] = unsafe { read(rsdp + 9u64) };
+    // The XSDT address is only covered by the extended checksum, the RSDT is there either way
+    let (root, entry_size) = if revision >= 2 && extended_checksum(rsdp) {
+        (PhysAddr::new(unsafe { read::<u64>(rsdp + 24u64) }), 8)
+    } else {
+        (
+            PhysAddr::new(unsafe { read::<u32>(rsdp + 16u64) } as u64),
+            4,
+        )
+    };
+
+    let mut acpi = Acpi {
+        revision,
+        oem_id,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+
+    let root = match Sdt::at(root) {
+        Some(root) => root,
+        None => return,
+    };
+    for i in 0..root.data_len() / entry_size {
+        let entry = root.data() + (i * entry_size) as u64;
+        let addr = match entry_size {
+            8 => unsafe { read::<u64>(entry) },
+            _ => unsafe { read::<u32>(entry) as u64 },
+        };
+        let sdt = match Sdt::at(PhysAddr::new(addr)) {
+            Some(sdt) => sdt,
+            None => continue,
+        };
+
+        match &sdt.signature {
+            b"APIC" => acpi.madt = Some(Madt::parse(&sdt)),
+            b"FACP" => acpi.fadt = Some(Fadt::parse(&sdt)),
+            b"HPET" => acpi.hpet = Some(Hpet::parse(&sdt)),
+            b"MCFG" => acpi.mcfg = Some(Mcfg::parse(&sdt)),
+            _ => (),
+        }
+    }
+
+    ACPI.init_once(|| acpi);
+}
+
+// Header common to every system description table
+struct Sdt {
+    addr: PhysAddr,
+    signature: [u8; 4],
+    len: usize,
+    revision: u8,
+}
+
+impl Sdt {
+    const HEADER_LEN: usize = 36;
+
+    fn at(addr: PhysAddr) -> Option<Self> {
+        let signature = unsafe { read(addr) };
+        let len = unsafe { read::<u32>(addr + 4u64) } as usize;
+        let revision = unsafe { read(addr + 8u64) };
+        if len < Self::HEADER_LEN || !checksum(addr, len) {
+            return None;
+        }
+
+        Some(Self {
+            addr,
+            signature,
+            len,
+            revision,
+        })
+    }
+
+    fn data(&self) -> PhysAddr {
+        self.addr + Self::HEADER_LEN as u64
+    }
+    fn data_len(&self) -> usize {
+        self.len - Self::HEADER_LEN
+    }
+
+    /// Reads a value at an offset from the start of the table, or returns `None` past its end
+    fn field<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + core::mem::size_of::<T>() > self.len {
+            return None;
+        }
+        Some(unsafe { read(self.addr + offset as u64) })
+    }
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    // The first KiB of the EBDA, then the BIOS read-only area
+    let ebda = (unsafe { read::<u16>(PhysAddr::new(0x40E)) } as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    areas
+        .iter()
+        .filter(|(start, _)| *start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| unsafe { read::<[u8; 8]>(addr) } == *b"RSD PTR " && checksum(addr, 20))
+}
+
+// ACPI 2.0 RSDPs checksum their whole `length`, which includes the XSDT address
+fn extended_checksum(rsdp: PhysAddr) -> bool {
+    let length = unsafe { read::<u32>(rsdp + 20u64) } as usize;
+    (36..=4096).contains(&length) && checksum(rsdp, length)
+}
+
+// Finds `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })` without a full AML interpreter
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let name = aml.windows(4).enumerate().position(|(i, w)| {
+        w == b"_S5_"
+            && aml.get(i + 4) == Some(&PACKAGE_OP)
+            && (i >= 1 && aml[i - 1] == NAME_OP
+                || i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == b'\\')
+    })?;
+
+    // PkgLength encodes how many bytes follow the lead byte in its top two bits
+    let mut i = name + 5;
+    i += 1 + (*aml.get(i)? >> 6) as usize;
+    // NumElements
+    i += 1;
+
+    let mut integer = || {
+        let value = match *aml.get(i)? {
+            BYTE_PREFIX => {
+                i += 1;
+                *aml.get(i)?
+            }
+            value => value,
+        };
+        i += 1;
+        Some(value)
+    };
+    let a = integer()?;
+    let b = integer()?;
+    Some((a, b))
+}
+
+fn checksum(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(virt(addr), len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn virt(addr: PhysAddr) -> *const u8 {
+    let offset = PHYS_OFFSET.try_get().expect("paging not initialized");
+    (*offset + addr.as_u64()).as_ptr()
+}
+
+/// # Safety
+/// The physical address has to point to a valid `T`
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(virt(addr) as *const T)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test_case]
+    fn rsdp() {
+        let rsdp = super::find_rsdp().expect("no RSDP");
+        if unsafe { super::read::<u8>(rsdp + 15u64) } >= 2 {
+            assert!(super::extended_checksum(rsdp));
+        }
+    }
+
+    #[test_case]
+    fn madt() {
+        let acpi = super::ACPI.try_get().expect("no ACPI tables");
+        let madt = acpi.madt.as_ref().expect("no MADT");
+        assert!(madt.processors.iter().any(|p| p.enabled));
+        assert!(!madt.io_apics.is_empty());
+    }
+
+    #[test_case]
+    fn fadt() {
+        let acpi = super::ACPI.try_get().expect("no ACPI tables");
+        let fadt = acpi.fadt.as_ref().expect("no FADT");
+        assert_ne!(fadt.pm1a_control_block, 0);
+        assert_ne!(fadt.dsdt.as_u64(), 0);
+        assert!(acpi.s5_sleep_type().is_some());
+    }
+
+    #[test_case]
+    fn find_s5() {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = [
+            0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00,
+        ];
+        assert_eq!(super::find_s5(&aml), Some((5, 5)));
+
+        // Name (\_S5, Package (0x04) { 0x07, Zero, Zero, Zero }) with a two byte PkgLength
+        let aml = [
+            0x08, 0x5C, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x48, 0x00, 0x04, 0x0A, 0x07, 0x00, 0x00,
+            0x00,
+        ];
+        assert_eq!(super::find_s5(&aml), Some((7, 0)));
+
+        // Method (_S5_) isn't a Name, and a truncated package has no values
+        let aml = [0x14, 0x06, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x03, 0x02];
+        assert_eq!(super::find_s5(&aml), None);
+        let aml = [0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x06];
+        assert_eq!(super::find_s5(&aml), None);
+    }
+}
@@ -0,0 +1,272 @@
+use super::Sdt;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub pcat_compat: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+impl Madt {
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        let mut madt = Self {
+            local_apic_address: PhysAddr::new(sdt.field::<u32>(36).unwrap_or(0) as u64),
+            pcat_compat: sdt.field::<u32>(40).unwrap_or(0) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = 44;
+        while let (Some(kind), Some(len)) = (sdt.field::<u8>(offset), sdt.field::<u8>(offset + 1)) {
+            let len = len as usize;
+            if len < 2 {
+                break;
+            }
+
+            match kind {
+                0 => {
+                    if let (Some(processor_id), Some(apic_id), Some(flags)) = (
+                        sdt.field(offset + 2),
+                        sdt.field(offset + 3),
+                        sdt.field::<u32>(offset + 4),
+                    ) {
+                        madt.processors.push(Processor {
+                            processor_id,
+                            apic_id,
+                            enabled: flags & 1 != 0,
+                        });
+                    }
+                }
+                1 => {
+                    if let (Some(id), Some(address), Some(gsi_base)) = (
+                        sdt.field(offset + 2),
+                        sdt.field::<u32>(offset + 4),
+                        sdt.field(offset + 8),
+                    ) {
+                        madt.io_apics.push(IoApic {
+                            id,
+                            address: PhysAddr::new(address as u64),
+                            gsi_base,
+                        });
+                    }
+                }
+                2 => {
+                    if let (Some(irq), Some(gsi), Some(flags)) = (
+                        sdt.field(offset + 3),
+                        sdt.field(offset + 4),
+                        sdt.field(offset + 8),
+                    ) {
+                        madt.overrides.push(InterruptOverride { irq, gsi, flags });
+                    }
+                }
+                5 => {
+                    if let Some(address) = sdt.field::<u64>(offset + 4) {
+                        madt.local_apic_address = PhysAddr::new(address);
+                    }
+                }
+                _ => (),
+            }
+
+            offset += len;
+        }
+
+        madt
+    }
+
+    /// Global system interrupt an ISA IRQ is wired to
+    pub fn isa_gsi(&self, irq: u8) -> u32 {
+        self.isa_override(irq).map_or(irq as u32, |o| o.gsi)
+    }
+    pub fn isa_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|o| o.irq == irq)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(sdt: &Sdt, offset: usize) -> Option<Self> {
+        Some(Self {
+            address_space: sdt.field(offset)?,
+            bit_width: sdt.field(offset + 1)?,
+            bit_offset: sdt.field(offset + 2)?,
+            access_size: sdt.field(offset + 3)?,
+            address: sdt.field(offset + 4)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century: u8,
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        let dsdt = match sdt.field::<u64>(140) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => sdt.field::<u32>(40).unwrap_or(0) as u64,
+        };
+        let flags = sdt.field(112).unwrap_or(0);
+
+        Self {
+            revision: sdt.revision,
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: sdt.field(46).unwrap_or(0),
+            smi_command_port: sdt.field(48).unwrap_or(0),
+            acpi_enable: sdt.field(52).unwrap_or(0),
+            acpi_disable: sdt.field(53).unwrap_or(0),
+            pm1a_event_block: sdt.field(56).unwrap_or(0),
+            pm1b_event_block: sdt.field(60).unwrap_or(0),
+            pm1a_control_block: sdt.field(64).unwrap_or(0),
+            pm1b_control_block: sdt.field(68).unwrap_or(0),
+            pm_timer_block: sdt.field(76).unwrap_or(0),
+            century: sdt.field(108).unwrap_or(0),
+            boot_architecture: sdt.field(109).unwrap_or(0),
+            flags,
+            reset_register: match flags & Self::RESET_REG_SUPPORTED {
+                0 => None,
+                _ => GenericAddress::parse(sdt, 116),
+            },
+            reset_value: sdt.field(128).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    pub address: GenericAddress,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        let block_id: u32 = sdt.field(36).unwrap_or(0);
+
+        Self {
+            hardware_revision: block_id as u8,
+            comparators: ((block_id >> 8) & 0b1_1111) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            vendor_id: (block_id >> 16) as u16,
+            address: GenericAddress::parse(sdt, 40).unwrap_or(GenericAddress {
+                address_space: GenericAddress::SYSTEM_MEMORY,
+                bit_width: 0,
+                bit_offset: 0,
+                access_size: 0,
+                address: 0,
+            }),
+            number: sdt.field(52).unwrap_or(0),
+            minimum_tick: sdt.field(53).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub segments: Vec<PciSegment>,
+}
+
+// Configuration space of a PCI segment group, mapped through ECAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciSegment {
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        const ENTRY_LEN: usize = 16;
+
+        let mut segments = Vec::new();
+        let mut offset = 44;
+        while offset + ENTRY_LEN <= sdt.len {
+            if let (Some(base), Some(segment), Some(start_bus), Some(end_bus)) = (
+                sdt.field::<u64>(offset),
+                sdt.field(offset + 8),
+                sdt.field(offset + 10),
+                sdt.field(offset + 11),
+            ) {
+                segments.push(PciSegment {
+                    base_address: PhysAddr::new(base),
+                    segment,
+                    start_bus,
+                    end_bus,
+                });
+            }
+            offset += ENTRY_LEN;
+        }
+
+        Self { segments }
+    }
+}
@@ -1,46 +1,73 @@
-use crate::sync::Lazy;
-use x86_64::{
-    structures::{
-        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
-        tss::TaskStateSegment,
-    },
-    VirtAddr,
+use crate::{mem::stack::Stack, sync::Once};
+use x86_64::structures::{
+    gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+    tss::TaskStateSegment,
 };
 
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// Sizes of the stacks switched to for the exceptions which can't trust the current one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IstStackSizes {
+    pub double_fault: usize,
+    pub nmi: usize,
+    pub machine_check: usize,
+    pub page_fault: usize,
+}
+
+impl Default for IstStackSizes {
+    fn default() -> Self {
+        Self {
+            double_fault: 16 * 1024,
+            nmi: 16 * 1024,
+            machine_check: 16 * 1024,
+            page_fault: 32 * 1024,
+        }
+    }
+}
+
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+static TSS: Once<TaskStateSegment> = Once::new();
+
 pub fn init() {
-    GDT.0.load();
+    init_with(IstStackSizes::default());
+}
+
+/// Paging and the heap have to be initialised since the stacks get guard pages
+pub fn init_with(sizes: IstStackSizes) {
+    let tss = TSS.init_once(|| {
+        let mut tss = TaskStateSegment::new();
+        let stacks = [
+            (DOUBLE_FAULT_IST_INDEX, sizes.double_fault),
+            (NMI_IST_INDEX, sizes.nmi),
+            (MACHINE_CHECK_IST_INDEX, sizes.machine_check),
+            (PAGE_FAULT_IST_INDEX, sizes.page_fault),
+        ];
+        for &(index, size) in &stacks {
+            let stack = Stack::allocate(size).expect("IST stack allocation failed");
+            tss.interrupt_stack_table[index as usize] = stack.leak();
+        }
+        tss
+    });
+
+    let (gdt, selectors) = GDT.init_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, Selectors { code, tss })
+    });
+
+    gdt.load();
     unsafe {
-        x86_64::instructions::segmentation::set_cs(GDT.1.code);
-        x86_64::instructions::tables::load_tss(GDT.1.tss);
+        x86_64::instructions::segmentation::set_cs(selectors.code);
+        x86_64::instructions::tables::load_tss(selectors.tss);
     }
 }
 
-static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-    (
-        gdt,
-        Selectors {
-            code: code_selector,
-            tss: tss_selector,
-        },
-    )
-});
-
 struct Selectors {
     code: SegmentSelector,
     tss: SegmentSelector,
 }
-
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
-    };
-    tss
-});
@@ -0,0 +1,223 @@
+use crate::{
+    acpi::ACPI,
+    mem::{
+        mmio::{CacheMode, Mmio},
+        Volatile,
+    },
+    sync::{Mutex, Once},
+    time::{pit, TIMER_HZ},
+};
+use core::arch::x86_64::__cpuid;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IO_APIC_BASE: u64 = 0xFEC0_0000;
+
+pub static LOCAL_APIC: Once<LocalApic> = Once::new();
+pub static IO_APIC: Once<Mutex<IoApic>> = Once::new();
+
+pub fn supported() -> bool {
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+/// # Safety
+/// The APIC has to be present and the legacy PIC masked
+pub unsafe fn init(timer_vector: u8, keyboard_vector: u8) {
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = base_msr.read();
+    base_msr.write(base | (1 << 11));
+
+    let phys = PhysAddr::new(base & 0x000F_FFFF_FFFF_F000);
+    let local = LocalApic {
+        regs: Mmio::map(phys, CacheMode::Uncached).expect("local APIC mapping failed"),
+    };
+    local.enable();
+    local.start_timer(timer_vector, TIMER_HZ);
+    LOCAL_APIC.init_once(|| local);
+
+    let madt = ACPI.try_get().and_then(|acpi| acpi.madt.as_ref());
+    let io_apic_base = madt
+        .and_then(|madt| madt.io_apics.first())
+        .map_or(PhysAddr::new(IO_APIC_BASE), |io| io.address);
+
+    let mut io = IoApic {
+        regs: Mmio::map(io_apic_base, CacheMode::Uncached).expect("I/O APIC mapping failed"),
+    };
+    for gsi in 0..io.entries() {
+        io.mask(gsi);
+    }
+    IO_APIC.init_once(|| Mutex::new(io));
+
+    route_isa(1, keyboard_vector);
+}
+
+/// Routes an ISA IRQ to `vector` on this CPU
+pub fn route_isa(irq: u8, vector: u8) {
+    let (local, io) = match (LOCAL_APIC.try_get(), IO_APIC.try_get()) {
+        (Some(local), Some(io)) => (local, io),
+        _ => return,
+    };
+    let madt = ACPI.try_get().and_then(|acpi| acpi.madt.as_ref());
+
+    // ISA interrupts are edge triggered and active high unless the firmware says otherwise
+    let gsi = madt.map_or(irq as u32, |madt| madt.isa_gsi(irq)) as u8;
+    let mut entry = IoApic::entry_for(vector, local.id());
+    if let Some(o) = madt.and_then(|madt| madt.isa_override(irq)) {
+        if o.active_low() {
+            entry |= IoApic::ACTIVE_LOW;
+        }
+        if o.level_triggered() {
+            entry |= IoApic::LEVEL_TRIGGERED;
+        }
+    }
+    io.lock().set_entry(gsi, entry);
+}
+
+// The APIC registers are 32 bits wide, each on its own 16 byte line
+#[repr(C, align(16))]
+struct Register(Volatile<u32>);
+
+impl Register {
+    fn read(&self) -> u32 {
+        self.0.read()
+    }
+    fn write(&self, val: u32) {
+        self.0.write(val)
+    }
+}
+
+#[repr(C)]
+struct LocalApicRegisters {
+    _reserved0: [Register; 2],
+    id: Register,
+    _reserved1: [Register; 8],
+    eoi: Register,
+    _reserved2: [Register; 3],
+    spurious: Register,
+    _reserved3: [Register; 34],
+    lvt_timer: Register,
+    _reserved4: [Register; 2],
+    lvt_lint0: Register,
+    lvt_lint1: Register,
+    lvt_error: Register,
+    timer_initial: Register,
+    timer_current: Register,
+    _reserved5: [Register; 4],
+    timer_divide: Register,
+}
+
+pub struct LocalApic {
+    regs: Mmio<LocalApicRegisters>,
+}
+
+impl LocalApic {
+    const MASKED: u32 = 1 << 16;
+    const PERIODIC: u32 = 1 << 17;
+
+    pub fn id(&self) -> u8 {
+        (self.regs.id.read() >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.regs.eoi.write(0)
+    }
+
+    unsafe fn enable(&self) {
+        self.regs.lvt_lint0.write(Self::MASKED);
+        self.regs.lvt_lint1.write(Self::MASKED);
+        self.regs.lvt_error.write(Self::MASKED);
+        self.regs.spurious.write(0x100 | SPURIOUS_VECTOR as u32);
+    }
+
+    // Counts how fast the timer runs against the PIT before making it periodic
+    unsafe fn start_timer(&self, vector: u8, hz: u64) {
+        const CALIBRATION_MICROS: u64 = 10_000;
+
+        let regs = &self.regs;
+        regs.timer_divide.write(0b0011);
+        regs.lvt_timer.write(Self::MASKED);
+        regs.timer_initial.write(u32::MAX);
+        pit::busy_wait(CALIBRATION_MICROS);
+        let elapsed = u32::MAX - regs.timer_current.read();
+        let per_second = elapsed as u64 * 1_000_000 / CALIBRATION_MICROS;
+
+        regs.lvt_timer.write(Self::PERIODIC | vector as u32);
+        regs.timer_initial.write((per_second / hz) as u32);
+    }
+}
+
+// Everything else is reached through the window
+#[repr(C)]
+struct IoApicRegisters {
+    select: Register,
+    window: Register,
+}
+
+pub struct IoApic {
+    regs: Mmio<IoApicRegisters>,
+}
+
+impl IoApic {
+    const VERSION: u32 = 0x01;
+    const REDIRECTION: u32 = 0x10;
+
+    pub const ACTIVE_LOW: u64 = 1 << 13;
+    pub const LEVEL_TRIGGERED: u64 = 1 << 15;
+    pub const MASKED: u64 = 1 << 16;
+
+    pub fn entries(&self) -> u8 {
+        ((self.read(Self::VERSION) >> 16) & 0xFF) as u8 + 1
+    }
+
+    /// Redirection entry delivering an interrupt as `vector` to the local APIC `dest`
+    pub fn entry_for(vector: u8, dest: u8) -> u64 {
+        (dest as u64) << 56 | vector as u64
+    }
+
+    pub fn route(&mut self, gsi: u8, vector: u8, dest: u8) {
+        self.set_entry(gsi, Self::entry_for(vector, dest));
+    }
+    pub fn mask(&mut self, gsi: u8) {
+        let entry = self.entry(gsi);
+        self.set_entry(gsi, entry | Self::MASKED);
+    }
+    pub fn unmask(&mut self, gsi: u8) {
+        let entry = self.entry(gsi);
+        self.set_entry(gsi, entry & !Self::MASKED);
+    }
+
+    pub fn entry(&self, gsi: u8) -> u64 {
+        let reg = Self::REDIRECTION + gsi as u32 * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+    pub fn set_entry(&mut self, gsi: u8, entry: u64) {
+        let reg = Self::REDIRECTION + gsi as u32 * 2;
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        self.regs.select.write(reg);
+        self.regs.window.read()
+    }
+    fn write(&self, reg: u32, val: u32) {
+        self.regs.select.write(reg);
+        self.regs.window.write(val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoApicRegisters, LocalApicRegisters};
+    use core::mem;
+
+    #[test_case]
+    fn register_layout() {
+        // The last local APIC register is the timer divide configuration at 0x3E0
+        assert_eq!(mem::size_of::<LocalApicRegisters>(), 0x3F0);
+        assert_eq!(mem::size_of::<IoApicRegisters>(), 0x20);
+    }
+}
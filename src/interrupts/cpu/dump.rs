@@ -0,0 +1,120 @@
+use super::ExceptionFrame;
+use crate::mem::paging::{self, PHYS_OFFSET};
+use core::fmt;
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3},
+    structures::{idt::PageFaultErrorCode, paging::mapper::MapperAllSizes},
+    VirtAddr,
+};
+
+const MAX_FRAMES: usize = 32;
+
+pub struct Registers<'a>(pub &'a ExceptionFrame);
+
+impl fmt::Display for Registers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = self.0;
+        let cr4: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)) };
+
+        writeln!(
+            f,
+            "rax {:016x} rbx {:016x} rcx {:016x}",
+            r.rax, r.rbx, r.rcx
+        )?;
+        writeln!(
+            f,
+            "rdx {:016x} rsi {:016x} rdi {:016x}",
+            r.rdx, r.rsi, r.rdi
+        )?;
+        writeln!(f, "rbp {:016x} rsp {:016x} r8  {:016x}", r.rbp, r.rsp, r.r8)?;
+        writeln!(f, "r9  {:016x} r10 {:016x} r11 {:016x}", r.r9, r.r10, r.r11)?;
+        writeln!(
+            f,
+            "r12 {:016x} r13 {:016x} r14 {:016x}",
+            r.r12, r.r13, r.r14
+        )?;
+        writeln!(
+            f,
+            "r15 {:016x} rip {:016x} rfl {:016x}",
+            r.r15, r.rip, r.rflags
+        )?;
+        writeln!(f, "cs  {:04x} ss  {:04x}", r.cs, r.ss)?;
+        write!(
+            f,
+            "cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            cr4,
+        )
+    }
+}
+
+pub struct ErrorCode<'a>(pub &'a ExceptionFrame);
+
+impl fmt::Display for ErrorCode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0.error_code;
+        match self.0.vector {
+            14 => write!(
+                f,
+                "error code {:#x}: {:?} accessing {:?}",
+                code,
+                PageFaultErrorCode::from_bits_truncate(code),
+                Cr2::read(),
+            ),
+            // Selector error codes
+            10..=13 | 17 if code != 0 => {
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "error code {:#x}: {} index {}{}",
+                    code,
+                    table,
+                    (code >> 3) & 0x1FFF,
+                    if code & 1 != 0 { ", external" } else { "" },
+                )
+            }
+            8 | 10..=14 | 17 | 30 => write!(f, "error code {:#x}", code),
+            _ => write!(f, "no error code"),
+        }
+    }
+}
+
+/// Follows the saved frame pointers, which needs the kernel built with them
+pub struct Backtrace<'a>(pub &'a ExceptionFrame);
+
+impl fmt::Display for Backtrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backtrace:\n  #0  {:016x}", self.0.rip)?;
+
+        let mut rbp = self.0.rbp;
+        for depth in 1..MAX_FRAMES {
+            if rbp == 0 || rbp % 8 != 0 || !readable(rbp) || !readable(rbp + 8) {
+                break;
+            }
+            let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if ret == 0 {
+                break;
+            }
+            write!(f, "\n  #{:<2} {:016x}", depth, ret)?;
+            rbp = next;
+        }
+        Ok(())
+    }
+}
+
+// Walks the page tables without the mapper lock, which the faulting code might hold
+fn readable(addr: u64) -> bool {
+    let (phys_offset, addr) = match (PHYS_OFFSET.try_get(), VirtAddr::try_new(addr)) {
+        (Some(phys_offset), Ok(addr)) => (*phys_offset, addr),
+        _ => return false,
+    };
+    let mapper = unsafe { paging::mapper(phys_offset) };
+    mapper.translate_addr(addr).is_some()
+}
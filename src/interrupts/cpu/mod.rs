@@ -0,0 +1,199 @@
+// The screen might not be visible, so fatal exceptions go to the serial port too
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        s1println!($($arg)*);
+    }};
+}
+
+mod dump;
+mod stubs;
+
+use crate::{
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, PAGE_FAULT_IST_INDEX},
+    mem::{
+        stack,
+        vm::{self, Fault},
+    },
+};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use stubs::*;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Registers saved by the exception stubs, then what the CPU pushed
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+const NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "SECURITY EXCEPTION",
+    "RESERVED",
+];
+
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    // The stubs aren't x86-interrupt functions, the IDT only cares about their address
+    unsafe fn stub<F>(stub: unsafe extern "C" fn()) -> F {
+        mem::transmute_copy(&stub)
+    }
+
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(exception_0));
+        idt.debug.set_handler_fn(stub(exception_1));
+        idt.non_maskable_interrupt
+            .set_handler_fn(stub(exception_2))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.breakpoint.set_handler_fn(stub(exception_3));
+        idt.overflow.set_handler_fn(stub(exception_4));
+        idt.bound_range_exceeded.set_handler_fn(stub(exception_5));
+        idt.invalid_opcode.set_handler_fn(stub(exception_6));
+        idt.device_not_available.set_handler_fn(stub(exception_7));
+        idt.double_fault
+            .set_handler_fn(stub(exception_8))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub(exception_10));
+        idt.segment_not_present.set_handler_fn(stub(exception_11));
+        idt.stack_segment_fault.set_handler_fn(stub(exception_12));
+        idt.general_protection_fault
+            .set_handler_fn(stub(exception_13));
+        idt.page_fault
+            .set_handler_fn(stub(exception_14))
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_fn(stub(exception_16));
+        idt.alignment_check.set_handler_fn(stub(exception_17));
+        idt.machine_check
+            .set_handler_fn(stub(exception_18))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_fn(stub(exception_19));
+        idt.virtualization.set_handler_fn(stub(exception_20));
+        idt.security_exception.set_handler_fn(stub(exception_30));
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let name = NAMES[frame.vector as usize % NAMES.len()];
+
+    match frame.vector {
+        3 => {
+            println!("EXCEPTION: BREAKPOINT");
+            println!("{}", dump::Registers(frame));
+        }
+        14 => page_fault(frame),
+        _ => fatal(name, frame),
+    }
+}
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+// Page faults being handled. The handler runs on its own IST stack, which a page fault in
+// the handler starts over at the top of, overwriting the frame of the one it interrupted.
+static PAGE_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+fn page_fault(frame: &ExceptionFrame) {
+    match PAGE_FAULTS.fetch_add(1, Ordering::SeqCst) {
+        0 => (),
+        // The interrupted handler can never return, so it's as fatal as a double fault
+        1 => fatal("PAGE FAULT IN PAGE FAULT HANDLER", frame),
+        // Reporting the nested fault faulted too
+        _ => crate::halt(),
+    }
+    resolve_page_fault(frame);
+    PAGE_FAULTS.fetch_sub(1, Ordering::SeqCst);
+}
+
+// Only returns if the access can be retried
+fn resolve_page_fault(frame: &ExceptionFrame) {
+    let addr = Cr2::read();
+    let err = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    match vm::handle_page_fault(addr, err) {
+        Fault::Mapped => return,
+        // The thread holding the lock blocked with it, retrying works once it runs again
+        Fault::Busy if frame.rflags & RFLAGS_IF != 0 => return,
+        Fault::Busy => fatal("PAGE FAULT WITH PAGE TABLES LOCKED", frame),
+        Fault::Deadlock => fatal("PAGE FAULT WHILE HOLDING THE PAGE TABLES", frame),
+        Fault::OutOfMemory => fatal("OUT OF MEMORY IN PAGE FAULT", frame),
+        Fault::Invalid => (),
+    }
+    if !err.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && stack::is_guard(addr) {
+        fatal("KERNEL STACK OVERFLOW", frame);
+    }
+    fatal("PAGE FAULT", frame);
+}
+
+/// Prints everything known about the exception to the screen and serial port, then panics
+fn fatal(name: &str, frame: &ExceptionFrame) -> ! {
+    report!("EXCEPTION: {} ({})", name, frame.vector);
+    report!("{}", dump::ErrorCode(frame));
+    report!("{}", dump::Registers(frame));
+    report!("{}", dump::Backtrace(frame));
+
+    panic!("EXCEPTION: {} at {:?}", name, VirtAddr::new(frame.rip));
+}
+
+#[cfg(test)]
+mod tests {
+    #[test_case]
+    fn breakpoint() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
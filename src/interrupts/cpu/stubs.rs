@@ -0,0 +1,165 @@
+// Every stub pushes a zero in place of the error code if the CPU doesn't push one, then its
+// vector, so the common part can save the registers and hand them over as an `ExceptionFrame`
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global exception_0
+exception_0:
+    push 0
+    push 0
+    jmp exception_common
+.global exception_1
+exception_1:
+    push 0
+    push 1
+    jmp exception_common
+.global exception_2
+exception_2:
+    push 0
+    push 2
+    jmp exception_common
+.global exception_3
+exception_3:
+    push 0
+    push 3
+    jmp exception_common
+.global exception_4
+exception_4:
+    push 0
+    push 4
+    jmp exception_common
+.global exception_5
+exception_5:
+    push 0
+    push 5
+    jmp exception_common
+.global exception_6
+exception_6:
+    push 0
+    push 6
+    jmp exception_common
+.global exception_7
+exception_7:
+    push 0
+    push 7
+    jmp exception_common
+.global exception_8
+exception_8:
+    push 8
+    jmp exception_common
+.global exception_10
+exception_10:
+    push 10
+    jmp exception_common
+.global exception_11
+exception_11:
+    push 11
+    jmp exception_common
+.global exception_12
+exception_12:
+    push 12
+    jmp exception_common
+.global exception_13
+exception_13:
+    push 13
+    jmp exception_common
+.global exception_14
+exception_14:
+    push 14
+    jmp exception_common
+.global exception_16
+exception_16:
+    push 0
+    push 16
+    jmp exception_common
+.global exception_17
+exception_17:
+    push 17
+    jmp exception_common
+.global exception_18
+exception_18:
+    push 0
+    push 18
+    jmp exception_common
+.global exception_19
+exception_19:
+    push 0
+    push 19
+    jmp exception_common
+.global exception_20
+exception_20:
+    push 0
+    push 20
+    jmp exception_common
+.global exception_30
+exception_30:
+    push 30
+    jmp exception_common
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    cld
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    call exception_dispatch
+    mov rsp, rbx
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+.att_syntax
+"#
+);
+
+extern "C" {
+    pub fn exception_0();
+    pub fn exception_1();
+    pub fn exception_2();
+    pub fn exception_3();
+    pub fn exception_4();
+    pub fn exception_5();
+    pub fn exception_6();
+    pub fn exception_7();
+    pub fn exception_8();
+    pub fn exception_10();
+    pub fn exception_11();
+    pub fn exception_12();
+    pub fn exception_13();
+    pub fn exception_14();
+    pub fn exception_16();
+    pub fn exception_17();
+    pub fn exception_18();
+    pub fn exception_19();
+    pub fn exception_20();
+    pub fn exception_30();
+}
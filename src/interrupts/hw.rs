@@ -1,25 +1,85 @@
-use crate::sync::{Lazy, Mutex};
+use super::apic;
+use crate::{
+    keyboard, mouse,
+    sync::{Mutex, Once},
+    thread, time,
+};
 use core::sync::atomic::Ordering;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use pic8259_simple::ChainedPics;
 use x86_64::{
     instructions::port::Port,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+pub static CONTROLLER: Once<Controller> = Once::new();
+
 pub fn init() {
+    // Remapping the PICs also keeps their spurious interrupts away from exceptions
     unsafe { PICS.lock().initialize() };
+
+    let controller = if cfg!(not(feature = "legacy-pic")) && apic::supported() {
+        unsafe {
+            mask_pics();
+            apic::init(InterruptIndex::Timer as u8, InterruptIndex::Keyboard as u8);
+            time::hpet::enable_interrupt(InterruptIndex::Hpet as u8);
+        }
+        apic::route_isa(12, InterruptIndex::Mouse as u8);
+        Controller::Apic
+    } else {
+        time::pit::set_frequency(time::TIMER_HZ);
+        unsafe { unmask_pic_irq(12) };
+        Controller::Pic
+    };
+    CONTROLLER.init_once(|| controller);
+
     x86_64::instructions::interrupts::enable();
 }
 
 pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
     idt[InterruptIndex::Timer.into()].set_handler_fn(timer_handler);
     idt[InterruptIndex::Keyboard.into()].set_handler_fn(keyboard_handler);
+    idt[InterruptIndex::Hpet.into()].set_handler_fn(hpet_handler);
+    idt[InterruptIndex::Mouse.into()].set_handler_fn(mouse_handler);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
 }
 
 static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+unsafe fn mask_pics() {
+    Port::<u8>::new(0x21).write(0xFF);
+    Port::<u8>::new(0xA1).write(0xFF);
+}
+
+// The firmware can leave lines masked, the secondary PIC also needs the cascade line
+unsafe fn unmask_pic_irq(irq: u8) {
+    let (port, line) = match irq {
+        0..=7 => (0x21, irq),
+        _ => {
+            let mut primary: Port<u8> = Port::new(0x21);
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+            (0xA1, irq - 8)
+        }
+    };
+    let mut port: Port<u8> = Port::new(port);
+    let mask = port.read();
+    port.write(mask & !(1 << line));
+}
+
+fn end_of_interrupt(index: InterruptIndex) {
+    match CONTROLLER.try_get() {
+        Some(Controller::Apic) => apic::LOCAL_APIC.try_get().unwrap().end_of_interrupt(),
+        _ => unsafe { PICS.lock().notify_end_of_interrupt(index as u8) },
+    }
+}
+
 const PIC_1_OFFSET: u8 = 32;
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
@@ -28,6 +88,9 @@ const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // Only delivered through the I/O APIC, in the PIC's range it would be the cascade
+    Hpet,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl From<InterruptIndex> for usize {
@@ -37,38 +100,34 @@ impl From<InterruptIndex> for usize {
 }
 
 extern "x86-interrupt" fn timer_handler(_: &mut InterruptStackFrame) {
-    crate::time::TICKS.fetch_add(1, Ordering::Relaxed);
+    let now = time::TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    time::timer::tick(now as u64);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
-    }
+    // Acknowledge first since this can switch to a thread which doesn't return through here
+    end_of_interrupt(InterruptIndex::Timer);
+    thread::tick(now as u64);
 }
 
 extern "x86-interrupt" fn keyboard_handler(_: &mut InterruptStackFrame) {
-    static KEYBOARD: Lazy<Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>>> = Lazy::new(|| {
-        Mutex::new(Keyboard::new(
-            layouts::Us104Key,
-            ScancodeSet1,
-            HandleControl::Ignore,
-        ))
-    });
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    keyboard::push_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn mouse_handler(_: &mut InterruptStackFrame) {
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    mouse::push_byte(byte);
+
+    end_of_interrupt(InterruptIndex::Mouse);
 }
+
+extern "x86-interrupt" fn hpet_handler(_: &mut InterruptStackFrame) {
+    time::hpet::interrupt();
+
+    end_of_interrupt(InterruptIndex::Hpet);
+}
+
+extern "x86-interrupt" fn spurious_handler(_: &mut InterruptStackFrame) {}
@@ -1,5 +1,6 @@
+pub mod apic;
 mod cpu;
-mod hw;
+pub mod hw;
 
 use crate::sync::Lazy;
 use x86_64::structures::idt::InterruptDescriptorTable;
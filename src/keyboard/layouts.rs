@@ -0,0 +1,61 @@
+use pc_keyboard::{
+    layouts::Us104Key, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers,
+};
+
+/// German QWERTZ, falling back to the US layout for the keys that are the same
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let shift = modifiers.lshift || modifiers.rshift;
+        let alt_gr = modifiers.alt_gr;
+        let pick = |normal, shifted, alt: Option<char>| match alt {
+            Some(alt) if alt_gr => DecodedKey::Unicode(alt),
+            _ if shift => DecodedKey::Unicode(shifted),
+            _ => DecodedKey::Unicode(normal),
+        };
+        let letter = |lower: char, upper: char| {
+            if shift != modifiers.capslock {
+                DecodedKey::Unicode(upper)
+            } else {
+                DecodedKey::Unicode(lower)
+            }
+        };
+
+        match keycode {
+            KeyCode::BackTick => pick('^', '°', None),
+            KeyCode::Key1 => pick('1', '!', None),
+            KeyCode::Key2 => pick('2', '"', Some('²')),
+            KeyCode::Key3 => pick('3', '§', Some('³')),
+            KeyCode::Key4 => pick('4', '$', None),
+            KeyCode::Key5 => pick('5', '%', None),
+            KeyCode::Key6 => pick('6', '&', None),
+            KeyCode::Key7 => pick('7', '/', Some('{')),
+            KeyCode::Key8 => pick('8', '(', Some('[')),
+            KeyCode::Key9 => pick('9', ')', Some(']')),
+            KeyCode::Key0 => pick('0', '=', Some('}')),
+            KeyCode::Minus => pick('ß', '?', Some('\\')),
+            KeyCode::Equals => pick('´', '`', None),
+            KeyCode::Q if alt_gr => DecodedKey::Unicode('@'),
+            KeyCode::E if alt_gr => DecodedKey::Unicode('€'),
+            KeyCode::M if alt_gr => DecodedKey::Unicode('µ'),
+            KeyCode::Y => Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            KeyCode::BracketSquareLeft => letter('ü', 'Ü'),
+            KeyCode::BracketSquareRight => pick('+', '*', Some('~')),
+            KeyCode::SemiColon => letter('ö', 'Ö'),
+            KeyCode::Quote => letter('ä', 'Ä'),
+            KeyCode::BackSlash => pick('#', '\'', None),
+            // The extra ISO key next to left shift
+            KeyCode::HashTilde => pick('<', '>', Some('|')),
+            KeyCode::Comma => pick(',', ';', None),
+            KeyCode::Fullstop => pick('.', ':', None),
+            KeyCode::Slash => pick('-', '_', None),
+            code => Us104Key::map_keycode(code, modifiers, handle_ctrl),
+        }
+    }
+}
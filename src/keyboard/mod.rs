@@ -0,0 +1,377 @@
+pub mod layouts;
+
+use crate::{
+    ps2::{self, Channel},
+    sync::{ArrayQueue, AtomicWaker, Mutex, Once},
+    task::Stream,
+};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use pc_keyboard::{DecodeState, KeyboardLayout, ScancodeSet as _, ScancodeSet1, ScancodeSet2};
+use x86_64::instructions::interrupts;
+
+pub use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState};
+
+const QUEUE_SIZE: usize = 128;
+
+static SCANCODES: Once<ArrayQueue<u8>> = Once::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static STATE: Mutex<State> = Mutex::new(State {
+    layout: Layout::Us,
+    scancode_set: ScancodeSet::Set1,
+    handle_control: HandleControl::MapLettersToUnicode,
+    decode: DecodeState::Start,
+    modifiers: Modifiers::new(),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    Azerty,
+    De,
+}
+
+impl Layout {
+    fn map(
+        self,
+        code: KeyCode,
+        modifiers: &Modifiers,
+        handle_control: HandleControl,
+    ) -> DecodedKey {
+        use pc_keyboard::layouts::{Azerty, Uk105Key, Us104Key};
+
+        let modifiers = modifiers.to_raw();
+        match self {
+            Self::Us => Us104Key::map_keycode(code, &modifiers, handle_control),
+            Self::Uk => Uk105Key::map_keycode(code, &modifiers, handle_control),
+            Self::Azerty => Azerty::map_keycode(code, &modifiers, handle_control),
+            Self::De => layouts::De105Key::map_keycode(code, &modifiers, handle_control),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// What the controller translates to by default
+    Set1,
+    /// What keyboards natively send, needs translation turned off
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Self {
+        Self {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    fn to_raw(self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.lshift,
+            rshift: self.rshift,
+            lctrl: self.lctrl,
+            rctrl: self.rctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+
+    fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// What the key produces with the current layout and modifiers, on presses
+    /// of keys other than modifiers only
+    pub key: Option<DecodedKey>,
+    /// State after this event
+    pub modifiers: Modifiers,
+}
+
+struct State {
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    handle_control: HandleControl,
+    decode: DecodeState,
+    modifiers: Modifiers,
+}
+
+impl State {
+    fn decode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = match self.scancode_set {
+            ScancodeSet::Set1 => ScancodeSet1::advance_state(&mut self.decode, scancode),
+            ScancodeSet::Set2 => ScancodeSet2::advance_state(&mut self.decode, scancode),
+        }
+        .ok()??;
+
+        let down = event.state == KeyState::Down;
+        let modifiers = &mut self.modifiers;
+        let is_modifier = match event.code {
+            KeyCode::ShiftLeft => {
+                modifiers.lshift = down;
+                true
+            }
+            KeyCode::ShiftRight => {
+                modifiers.rshift = down;
+                true
+            }
+            KeyCode::ControlLeft => {
+                modifiers.lctrl = down;
+                true
+            }
+            KeyCode::ControlRight => {
+                modifiers.rctrl = down;
+                true
+            }
+            KeyCode::AltLeft => {
+                modifiers.alt = down;
+                true
+            }
+            KeyCode::AltRight => {
+                modifiers.alt_gr = down;
+                true
+            }
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => {
+                if down {
+                    match event.code {
+                        KeyCode::CapsLock => modifiers.caps_lock = !modifiers.caps_lock,
+                        KeyCode::NumpadLock => modifiers.num_lock = !modifiers.num_lock,
+                        _ => modifiers.scroll_lock = !modifiers.scroll_lock,
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+
+        let key = match (down, is_modifier) {
+            (true, false) => Some(self.layout.map(
+                event.code,
+                &self.modifiers,
+                self.handle_control,
+            )),
+            _ => None,
+        };
+        Some(KeyEvent {
+            code: event.code,
+            state: event.state,
+            key,
+            modifiers: self.modifiers,
+        })
+    }
+}
+
+pub fn init() {
+    SCANCODES.init_once(|| ArrayQueue::new(QUEUE_SIZE));
+}
+
+/// Queues a scancode from the interrupt handler, dropping it if nobody keeps up
+pub(crate) fn push_scancode(scancode: u8) {
+    // Replies to the commands sent to the keyboard
+    if scancode == ps2::ACK || scancode == ps2::RESEND {
+        return;
+    }
+
+    if let Some(queue) = SCANCODES.try_get() {
+        if queue.push(scancode).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+pub fn layout() -> Layout {
+    STATE.lock().layout
+}
+pub fn set_layout(layout: Layout) {
+    STATE.lock().layout = layout;
+}
+
+/// Whether Ctrl with a letter gives the matching control character or the letter
+pub fn set_handle_control(handle_control: HandleControl) {
+    STATE.lock().handle_control = handle_control;
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    STATE.lock().scancode_set
+}
+/// Turns the controller's translation to set 1 on or off to match
+pub fn set_scancode_set(set: ScancodeSet) {
+    let mut state = STATE.lock();
+    interrupts::without_interrupts(|| {
+        let config = match ps2::read_config() {
+            Some(config) => config,
+            None => return,
+        };
+        unsafe {
+            ps2::write_config(match set {
+                ScancodeSet::Set1 => config | ps2::CONFIG_TRANSLATION,
+                ScancodeSet::Set2 => config & !ps2::CONFIG_TRANSLATION,
+            })
+        };
+        state.scancode_set = set;
+        state.decode = DecodeState::Start;
+    });
+}
+
+pub fn modifiers() -> Modifiers {
+    STATE.lock().modifiers
+}
+
+/// Sets the lock keys' state and their LEDs
+pub fn set_locks(caps_lock: bool, num_lock: bool, scroll_lock: bool) {
+    let leds = {
+        let mut state = STATE.lock();
+        state.modifiers.caps_lock = caps_lock;
+        state.modifiers.num_lock = num_lock;
+        state.modifiers.scroll_lock = scroll_lock;
+        state.modifiers.leds()
+    };
+    set_leds(leds);
+}
+
+// Takes a few milliseconds of waiting for the keyboard, so never with `STATE` locked
+fn set_leds(leds: u8) {
+    const SET_LEDS: u8 = 0xED;
+
+    // With interrupts off the acknowledgements come here instead of the handler
+    interrupts::without_interrupts(|| unsafe {
+        ps2::send(Channel::First, SET_LEDS) && ps2::send(Channel::First, leds)
+    });
+}
+
+/// Decodes queued scancodes until one completes a key event
+pub fn try_read_key() -> Option<KeyEvent> {
+    let queue = SCANCODES.try_get()?;
+    let mut state = STATE.lock();
+
+    while let Some(scancode) = queue.pop() {
+        let leds = state.modifiers.leds();
+        if let Some(event) = state.decode(scancode) {
+            drop(state);
+            if event.modifiers.leds() != leds {
+                set_leds(event.modifiers.leds());
+            }
+            return Some(event);
+        }
+    }
+    None
+}
+
+/// Halts until a key event comes in
+pub fn read_key() -> KeyEvent {
+    loop {
+        if let Some(event) = try_read_key() {
+            return event;
+        }
+
+        interrupts::disable();
+        if SCANCODES.try_get().map_or(true, |queue| queue.is_empty()) {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Key events as they come in
+///
+/// Every stream and `read_key` consume from the same queue, and only the
+/// last stream polled is woken.
+pub fn keys() -> Keys {
+    Keys(())
+}
+
+pub struct Keys(());
+
+impl Stream for Keys {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        if let Some(event) = try_read_key() {
+            return Poll::Ready(Some(event));
+        }
+
+        // Check again after registering so a scancode pushed in between isn't missed
+        WAKER.register(cx.waker());
+        match try_read_key() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodedKey, KeyCode, KeyState, Layout};
+
+    #[test_case]
+    fn decode() {
+        // Make code then break code for A
+        super::push_scancode(0x1E);
+        super::push_scancode(0x9E);
+
+        let press = super::read_key();
+        assert_eq!(press.code, KeyCode::A);
+        assert_eq!(press.state, KeyState::Down);
+        assert_eq!(press.key, Some(DecodedKey::Unicode('a')));
+
+        let release = super::read_key();
+        assert_eq!(release.state, KeyState::Up);
+        assert_eq!(release.key, None);
+    }
+
+    #[test_case]
+    fn modifiers() {
+        super::set_layout(Layout::De);
+        // Left shift, Y, then their break codes
+        for &scancode in &[0x2A, 0x15, 0x95, 0xAA] {
+            super::push_scancode(scancode);
+        }
+
+        let shift = super::read_key();
+        assert_eq!(shift.key, None);
+        assert!(shift.modifiers.shift());
+        assert_eq!(super::read_key().key, Some(DecodedKey::Unicode('Z')));
+        super::read_key();
+        assert!(!super::read_key().modifiers.shift());
+
+        super::set_layout(Layout::Us);
+    }
+}
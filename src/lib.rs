@@ -3,6 +3,7 @@
 #![feature(const_fn)]
 #![feature(const_in_array_repeat_expressions)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
@@ -14,12 +15,19 @@ extern crate alloc;
 #[macro_use]
 mod macros;
 
+pub mod acpi;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
 pub mod mem;
+pub mod mouse;
+pub mod power;
+pub mod ps2;
 pub mod rand;
 pub mod serial;
 pub mod sync;
+pub mod task;
+pub mod thread;
 pub mod time;
 pub mod vga;
 
@@ -29,20 +37,21 @@ pub mod test;
 #[cfg(test)]
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static bootloader::BootInfo) -> ! {
-    init();
-
-    let phys_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { mem::paging::mapper(phys_offset) };
-    let mut frame_allocator = unsafe { mem::paging::frame_allocator(&boot_info.memory_map) };
-
-    mem::alloc::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    init(boot_info);
 
     _test();
     halt()
 }
 
-pub fn init() {
+pub fn init(boot_info: &'static bootloader::BootInfo) {
+    mem::init(boot_info);
     gdt::init();
+    acpi::init();
+    time::init();
+    ps2::init();
+    keyboard::init();
+    mouse::init();
+    thread::init();
     interrupts::init();
 }
 
@@ -7,34 +7,55 @@
 extern crate alloc;
 
 use bootloader::BootInfo;
-use obamas::println;
-use x86_64::VirtAddr;
+use obamas::{
+    keyboard::{self, DecodedKey, KeyCode, KeyState},
+    power, print, println,
+    task::{Executor, Stream},
+};
 
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
-    obamas::init();
+    obamas::init(boot_info);
 
     println!("ObamaS booted successfully");
 
-    let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { obamas::mem::paging::mapper(phys_offset) };
-    let mut frame_allocator =
-        unsafe { obamas::mem::paging::frame_allocator(&boot_info.memory_map) };
-
-    obamas::mem::alloc::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
-
     #[cfg(test)]
     _test();
 
-    obamas::halt();
+    let mut executor = Executor::new();
+    executor.spawn(echo_keys());
+    executor.run();
+}
+
+async fn echo_keys() {
+    let mut keys = keyboard::keys();
+    while let Some(event) = keys.next().await {
+        let modifiers = event.modifiers;
+        if event.state == KeyState::Down && modifiers.ctrl() && modifiers.alt {
+            match event.code {
+                KeyCode::Delete => power::reboot(),
+                KeyCode::End => power::shutdown(),
+                _ => (),
+            }
+        }
+
+        match event.key {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => (),
+        }
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // The keyboard is polled from here on
+    x86_64::instructions::interrupts::disable();
     println!("{}", info);
-    obamas::halt();
+    println!("Press any key to reboot");
+    obamas::ps2::wait_for_keyboard();
+    power::reboot();
 }
 
 #[cfg(test)]
@@ -1,122 +1,675 @@
+use super::vm::{self, RegionKind, VmError};
 use crate::sync::Mutex;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{
     mem,
     ptr::{self, NonNull},
 };
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
 
 #[global_allocator]
-static ALLOCATOR: Mutex<BlockAlloc> = Mutex::new(BlockAlloc::new());
+static ALLOCATOR: Mutex<SlabAlloc> = Mutex::new(SlabAlloc::new());
+
+const SIZE_CLASSES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_SIZE: usize = 16 * 1024;
+
+struct Object {
+    next: Option<&'static mut Object>,
+}
+
+// Header at the start of every slab, which is aligned to its size so
+// the slab an object belongs to can be found by masking its address
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<&'static mut Object>,
+    used: usize,
+}
+
+impl Slab {
+    fn capacity(size: usize) -> usize {
+        (SLAB_SIZE - Self::first_object(size)) / size
+    }
+    fn first_object(size: usize) -> usize {
+        align_up(mem::size_of::<Self>(), size)
+    }
+
+    unsafe fn of(ptr: *mut u8) -> NonNull<Slab> {
+        NonNull::new_unchecked((ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SizeClass {
+    // Slabs with at least one free object
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    used: usize,
+}
+
+impl SizeClass {
+    const fn new() -> Self {
+        Self {
+            partial: None,
+            slabs: 0,
+            used: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        slab.as_mut().prev = None;
+        slab.as_mut().next = self.partial;
+        if let Some(mut next) = self.partial {
+            next.as_mut().prev = Some(slab);
+        }
+        self.partial = Some(slab);
+    }
+    unsafe fn remove(&mut self, slab: NonNull<Slab>) {
+        let slab = slab.as_ref();
+        match slab.prev {
+            Some(mut prev) => prev.as_mut().next = slab.next,
+            None => self.partial = slab.next,
+        }
+        if let Some(mut next) = slab.next {
+            next.as_mut().prev = slab.prev;
+        }
+    }
+}
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Usage of the slabs of one size class
+///
+/// With `heap-debug` every allocation is padded with a header and redzones, so
+/// objects land in the class of their padded size rather than the requested one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub size: usize,
+    pub slabs: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub max_size: usize,
+    pub used: usize,
+    pub peak: usize,
+    pub live: usize,
+    pub allocations: usize,
+    pub failed: usize,
+    /// Counted by padded size with `heap-debug`, unlike `used` and `peak`
+    pub size_classes: [SizeClassStats; SIZE_CLASSES.len()],
+}
 
-struct Block {
-    next: Option<&'static mut Block>,
+#[derive(Clone, Copy)]
+struct Counters {
+    used: usize,
+    peak: usize,
+    live: usize,
+    allocations: usize,
+    failed: usize,
 }
-pub struct BlockAlloc {
-    heads: [Option<&'static mut Block>; BLOCK_SIZES.len()],
+
+pub struct SlabAlloc {
+    classes: [SizeClass; SIZE_CLASSES.len()],
     fallback: linked_list_allocator::Heap,
+    max_size: usize,
+    counters: Counters,
+    #[cfg(feature = "heap-trace")]
+    trace: trace::Trace,
 }
+unsafe impl Send for SlabAlloc {}
 
-impl BlockAlloc {
+impl SlabAlloc {
     pub const fn new() -> Self {
         Self {
-            heads: [None; BLOCK_SIZES.len()],
+            classes: [SizeClass::new(); SIZE_CLASSES.len()],
             fallback: linked_list_allocator::Heap::empty(),
+            max_size: HEAP_MAX_SIZE,
+            counters: Counters {
+                used: 0,
+                peak: 0,
+                live: 0,
+                allocations: 0,
+                failed: 0,
+            },
+            #[cfg(feature = "heap-trace")]
+            trace: trace::Trace::new(),
         }
     }
     unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback.init(heap_start, heap_size);
     }
 
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.raw_alloc(layout)
+    }
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        debug::guard(self.raw_alloc(debug::outer(layout)), layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.raw_dealloc(ptr, layout)
+    }
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.raw_dealloc(debug::unguard(ptr, layout), debug::outer(layout))
+    }
+
+    unsafe fn raw_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            Some(idx) => self.slab_alloc(idx),
+            None => self.fallback_alloc(layout),
+        }
+    }
+    unsafe fn raw_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(idx) => self.slab_dealloc(idx, ptr),
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    unsafe fn slab_alloc(&mut self, idx: usize) -> *mut u8 {
+        let slab = match self.classes[idx].partial {
+            Some(slab) => slab,
+            None => match self.new_slab(idx) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+
+        let slab_ref = &mut *slab.as_ptr();
+        let object = slab_ref.free.take().unwrap();
+        slab_ref.free = object.next.take();
+        slab_ref.used += 1;
+        if slab_ref.free.is_none() {
+            self.classes[idx].remove(slab);
+        }
+        self.classes[idx].used += 1;
+
+        object as *mut Object as *mut u8
+    }
+
+    unsafe fn slab_dealloc(&mut self, idx: usize, ptr: *mut u8) {
+        let slab = Slab::of(ptr);
+        let class = &mut self.classes[idx];
+        let slab_ref = &mut *slab.as_ptr();
+
+        let was_full = slab_ref.free.is_none();
+        #[allow(clippy::cast_ptr_alignment)]
+        let object = ptr as *mut Object;
+        object.write(Object {
+            next: slab_ref.free.take(),
+        });
+        slab_ref.free = Some(&mut *object);
+        slab_ref.used -= 1;
+        class.used -= 1;
+
+        if was_full {
+            class.push(slab);
+        }
+        // Keep the last slab around so a single object doesn't keep bouncing
+        if slab_ref.used == 0 && class.slabs > 1 {
+            class.remove(slab);
+            class.slabs -= 1;
+            self.fallback.deallocate(
+                slab.cast(),
+                Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE),
+            );
+        }
+    }
+
+    unsafe fn new_slab(&mut self, idx: usize) -> Option<NonNull<Slab>> {
+        let size = SIZE_CLASSES[idx];
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let base = NonNull::new(self.fallback_alloc(layout))?;
+
+        let mut free = None;
+        for i in (0..Slab::capacity(size)).rev() {
+            #[allow(clippy::cast_ptr_alignment)]
+            let object = base.as_ptr().add(Slab::first_object(size) + i * size) as *mut Object;
+            object.write(Object { next: free });
+            free = Some(&mut *object);
+        }
+
+        let slab = base.cast::<Slab>();
+        slab.as_ptr().write(Slab {
+            prev: None,
+            next: None,
+            free,
+            used: 0,
+        });
+        self.classes[idx].push(slab);
+        self.classes[idx].slabs += 1;
+
+        Some(slab)
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // The free space at the top of the heap might not be aligned
+        if !self.grow(layout.size() + layout.align()) {
+            return ptr::null_mut();
+        }
         match self.fallback.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    fn grow(&mut self, by: usize) -> bool {
+        let size = self.fallback.size();
+        let by = align_up(by.max(HEAP_GROW_STEP), PAGE_SIZE);
+        if size + by > self.max_size {
+            return false;
+        }
+
+        // Pages past the initial heap are mapped when first touched
+        unsafe { self.fallback.extend(by) };
+        true
+    }
+
+    fn record_alloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let counters = &mut self.counters;
+        if ptr.is_null() {
+            counters.failed += 1;
+            return;
+        }
+
+        counters.used += layout.size();
+        counters.peak = counters.peak.max(counters.used);
+        counters.live += 1;
+        counters.allocations += 1;
+
+        #[cfg(feature = "heap-trace")]
+        self.trace.insert(ptr as usize, layout, trace::backtrace());
+    }
+    #[cfg_attr(not(feature = "heap-trace"), allow(unused_variables))]
+    fn record_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.counters.used -= layout.size();
+        self.counters.live -= 1;
+
+        #[cfg(feature = "heap-trace")]
+        self.trace.remove(ptr as usize);
+    }
+
+    fn size_class_stats(&self) -> [SizeClassStats; SIZE_CLASSES.len()] {
+        let mut stats = [SizeClassStats {
+            size: 0,
+            slabs: 0,
+            used: 0,
+            free: 0,
+        }; SIZE_CLASSES.len()];
+        for (i, class) in self.classes.iter().enumerate() {
+            let size = SIZE_CLASSES[i];
+            stats[i] = SizeClassStats {
+                size,
+                slabs: class.slabs,
+                used: class.used,
+                free: class.slabs * Slab::capacity(size) - class.used,
+            };
+        }
+        stats
+    }
 }
 
-unsafe impl GlobalAlloc for Mutex<BlockAlloc> {
+unsafe impl GlobalAlloc for Mutex<SlabAlloc> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match head(&layout) {
-            Some(idx) => match allocator.heads[idx].take() {
-                Some(block) => {
-                    allocator.heads[idx] = block.next.take();
-                    block as *mut Block as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[idx];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+        let ptr = allocator.alloc(layout);
+        allocator.record_alloc(ptr, layout);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
-        match head(&layout) {
-            Some(idx) => {
-                assert!(mem::size_of::<Block>() <= BLOCK_SIZES[idx]);
-                assert!(mem::align_of::<Block>() <= BLOCK_SIZES[idx]);
-
-                let new_block = Block {
-                    next: allocator.heads[idx].take(),
-                };
-                #[allow(clippy::cast_ptr_alignment)]
-                let mew_block_ptr = ptr as *mut Block;
-                mew_block_ptr.write(new_block);
-                allocator.heads[idx] = Some(&mut *mew_block_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback.deallocate(ptr, layout);
+        #[cfg(feature = "heap-debug")]
+        {
+            if let Err(corruption) = debug::check(ptr, layout) {
+                // Panicking might allocate, which would deadlock with the lock held
+                drop(allocator);
+                panic!("heap corruption: {} {:p} ({:?})", corruption, ptr, layout);
             }
         }
+        allocator.dealloc(ptr, layout);
+        allocator.record_dealloc(ptr, layout);
     }
 }
 
-fn head(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+fn size_class(layout: &Layout) -> Option<usize> {
+    let required_size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&s| s >= required_size)
+}
+
+fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
 }
 
-pub const HEAP_START: usize = 0x4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+const HEAP_GROW_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+/// Reserves room for the heap to grow to `HEAP_MAX_SIZE`, mapping `HEAP_SIZE` of it
+pub fn init_heap() -> Result<(), VmError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vm::reserve(HEAP_MAX_SIZE as u64, 0, RegionKind::Heap, flags, true)?;
+    vm::map(region.start, HEAP_SIZE as u64)?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR
+            .lock()
+            .init(region.start.as_u64() as usize, HEAP_SIZE);
     }
 
     Ok(())
 }
+
+/// Current size of the heap, including free space
+pub fn size() -> usize {
+    ALLOCATOR.lock().fallback.size()
+}
+
+pub fn size_classes() -> [SizeClassStats; SIZE_CLASSES.len()] {
+    ALLOCATOR.lock().size_class_stats()
+}
+
+pub fn stats() -> HeapStats {
+    let allocator = ALLOCATOR.lock();
+    let counters = allocator.counters;
+    HeapStats {
+        size: allocator.fallback.size(),
+        max_size: allocator.max_size,
+        used: counters.used,
+        peak: counters.peak,
+        live: counters.live,
+        allocations: counters.allocations,
+        failed: counters.failed,
+        size_classes: allocator.size_class_stats(),
+    }
+}
+
+/// Sets the size past which the heap won't grow anymore, at most `HEAP_MAX_SIZE`
+pub fn set_max_size(max_size: usize) {
+    ALLOCATOR.lock().max_size = max_size.min(HEAP_MAX_SIZE);
+}
+
+// Every allocation is laid out as
+// | allocator bookkeeping | size | state | redzone | data | redzone |
+// with the data aligned as requested, so overflows and frees can be checked
+#[cfg(feature = "heap-debug")]
+mod debug {
+    use super::align_up;
+    use alloc::alloc::Layout;
+    use core::{fmt, mem, ptr, slice};
+
+    const REDZONE: usize = 16;
+    const REDZONE_BYTE: u8 = 0xFD;
+    const UNINIT_BYTE: u8 = 0xCD;
+    const POISON_BYTE: u8 = 0xDD;
+
+    const LIVE: usize = 0x1111_7E11_1111_7E11;
+    const FREED: usize = 0xF4EE_DF4E_EDF4_EEDF;
+
+    // The first words are left alone since freed slab objects and
+    // fallback heap holes store their links there
+    #[repr(C)]
+    struct Header {
+        reserved: [usize; 2],
+        size: usize,
+        state: usize,
+    }
+
+    fn front(layout: Layout) -> usize {
+        align_up(mem::size_of::<Header>() + REDZONE, layout.align())
+    }
+
+    pub(super) fn outer(layout: Layout) -> Layout {
+        let size = front(layout) + layout.size() + REDZONE;
+        let align = layout.align().max(mem::align_of::<Header>());
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    pub(super) unsafe fn guard(block: *mut u8, layout: Layout) -> *mut u8 {
+        if block.is_null() {
+            return block;
+        }
+
+        let front = front(layout);
+        #[allow(clippy::cast_ptr_alignment)]
+        let header = &mut *(block as *mut Header);
+        header.size = layout.size();
+        header.state = LIVE;
+
+        let header_size = mem::size_of::<Header>();
+        ptr::write_bytes(block.add(header_size), REDZONE_BYTE, front - header_size);
+        ptr::write_bytes(block.add(front), UNINIT_BYTE, layout.size());
+        ptr::write_bytes(block.add(front + layout.size()), REDZONE_BYTE, REDZONE);
+
+        block.add(front)
+    }
+
+    pub(super) enum Corruption {
+        DoubleFree,
+        Unallocated,
+        Size(usize),
+        Underflow,
+        Overflow,
+    }
+
+    impl fmt::Display for Corruption {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Corruption::DoubleFree => write!(f, "double free of"),
+                Corruption::Unallocated => write!(f, "free of unallocated or overwritten"),
+                Corruption::Size(size) => write!(f, "size {} allocation freed as", size),
+                Corruption::Underflow => write!(f, "underflow before"),
+                Corruption::Overflow => write!(f, "overflow past"),
+            }
+        }
+    }
+
+    /// Checks that `ptr` is a live allocation of `layout` whose redzones are intact
+    pub(super) unsafe fn check(ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        let front = front(layout);
+        let block = ptr.sub(front);
+        #[allow(clippy::cast_ptr_alignment)]
+        let header = &*(block as *const Header);
+
+        match header.state {
+            LIVE => (),
+            FREED => return Err(Corruption::DoubleFree),
+            _ => return Err(Corruption::Unallocated),
+        }
+        if header.size != layout.size() {
+            return Err(Corruption::Size(header.size));
+        }
+
+        let header_size = mem::size_of::<Header>();
+        let before = slice::from_raw_parts(block.add(header_size), front - header_size);
+        if before.iter().any(|&b| b != REDZONE_BYTE) {
+            return Err(Corruption::Underflow);
+        }
+        let after = slice::from_raw_parts(ptr.add(layout.size()), REDZONE);
+        if after.iter().any(|&b| b != REDZONE_BYTE) {
+            return Err(Corruption::Overflow);
+        }
+
+        Ok(())
+    }
+
+    /// Poisons an allocation which passed `check`, returning the block to free
+    pub(super) unsafe fn unguard(ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let block = ptr.sub(front(layout));
+        #[allow(clippy::cast_ptr_alignment)]
+        let header = &mut *(block as *mut Header);
+
+        ptr::write_bytes(ptr, POISON_BYTE, layout.size());
+        header.state = FREED;
+
+        block
+    }
+}
+
+#[cfg(feature = "heap-trace")]
+pub use trace::{checkpoint, leaks_since, Allocation};
+
+// Records every live allocation so leaks can be listed after the fact, in a hash table
+// keyed by address since it's updated on every allocation and free
+#[cfg(feature = "heap-trace")]
+mod trace {
+    use super::ALLOCATOR;
+    use crate::mem::paging;
+    use alloc::{alloc::Layout, vec::Vec};
+    use x86_64::VirtAddr;
+
+    const BITS: u32 = 10;
+    const CAPACITY: usize = 1 << BITS;
+    const DEPTH: usize = 8;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Allocation {
+        pub id: usize,
+        pub addr: usize,
+        pub layout: Layout,
+        /// Return addresses of the innermost frames when it was made, starting inside the
+        /// allocator, and 0 past the outermost one
+        pub backtrace: [usize; DEPTH],
+    }
+
+    pub(super) struct Trace {
+        records: [Option<Allocation>; CAPACITY],
+        len: usize,
+        next_id: usize,
+        dropped: usize,
+    }
+
+    impl Trace {
+        pub(super) const fn new() -> Self {
+            Self {
+                records: [None; CAPACITY],
+                len: 0,
+                next_id: 0,
+                dropped: 0,
+            }
+        }
+
+        pub(super) fn insert(&mut self, addr: usize, layout: Layout, backtrace: [usize; DEPTH]) {
+            let id = self.next_id;
+            self.next_id += 1;
+            if self.len == CAPACITY {
+                self.dropped += 1;
+                return;
+            }
+
+            let mut idx = slot(addr);
+            while self.records[idx].is_some() {
+                idx = (idx + 1) % CAPACITY;
+            }
+            self.records[idx] = Some(Allocation {
+                id,
+                addr,
+                layout,
+                backtrace,
+            });
+            self.len += 1;
+        }
+        pub(super) fn remove(&mut self, addr: usize) {
+            let mut idx = slot(addr);
+            for _ in 0..CAPACITY {
+                match self.records[idx] {
+                    Some(record) if record.addr == addr => break,
+                    Some(_) => idx = (idx + 1) % CAPACITY,
+                    // Dropped when the table was full
+                    None => return,
+                }
+            }
+            if self.records[idx].map(|r| r.addr) != Some(addr) {
+                return;
+            }
+            self.records[idx] = None;
+            self.len -= 1;
+
+            // Moves later records of the run back into the hole if it's between them and
+            // their slot, so lookups never stop short of them
+            let mut hole = idx;
+            let mut next = (idx + 1) % CAPACITY;
+            while let Some(record) = self.records[next] {
+                let home = slot(record.addr);
+                if next.wrapping_sub(home) % CAPACITY >= next.wrapping_sub(hole) % CAPACITY {
+                    self.records[hole] = self.records[next].take();
+                    hole = next;
+                }
+                next = (next + 1) % CAPACITY;
+            }
+        }
+
+        fn between(&self, start: usize, end: usize) -> impl Iterator<Item = &Allocation> {
+            self.records
+                .iter()
+                .filter_map(Option::as_ref)
+                .filter(move |a| a.id >= start && a.id < end)
+        }
+    }
+
+    // Spreads out addresses, which are at least 8 byte aligned and often close together
+    fn slot(addr: usize) -> usize {
+        ((addr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - BITS)) as usize
+    }
+
+    // Follows the saved frame pointers up from here, checking each frame is mapped since
+    // the outermost one can be anything
+    pub(super) fn backtrace() -> [usize; DEPTH] {
+        let mut frames = [0; DEPTH];
+        let mut rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        for frame in frames.iter_mut() {
+            if rbp == 0 || rbp % 8 != 0 || !mapped(rbp) || !mapped(rbp + 8) {
+                break;
+            }
+            let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if ret == 0 {
+                break;
+            }
+            *frame = ret as usize;
+            rbp = next;
+        }
+        frames
+    }
+
+    fn mapped(addr: u64) -> bool {
+        VirtAddr::try_new(addr).map_or(false, |addr| paging::flags(addr).is_some())
+    }
+
+    /// Identifies the allocations made from now on
+    pub fn checkpoint() -> usize {
+        ALLOCATOR.lock().trace.next_id
+    }
+
+    /// Allocations made since the checkpoint which are still live, oldest first
+    pub fn leaks_since(checkpoint: usize) -> Vec<Allocation> {
+        let (end, count) = {
+            let trace = &ALLOCATOR.lock().trace;
+            let end = trace.next_id;
+            (end, trace.between(checkpoint, end).count())
+        };
+        // Allocating the list itself takes the lock, so it has to happen in between
+        let mut leaks = Vec::with_capacity(count);
+
+        let allocator = ALLOCATOR.lock();
+        leaks.extend(allocator.trace.between(checkpoint, end).copied());
+        drop(allocator);
+
+        leaks.sort_unstable_by_key(|a| a.id);
+        leaks
+    }
+}
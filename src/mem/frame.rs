@@ -0,0 +1,280 @@
+use crate::sync::{IrqMutex, Once};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub static FRAMES: Once<IrqMutex<FrameAlloc>> = Once::new();
+
+const FRAME_SIZE: usize = Size4KiB::SIZE as usize;
+const BITS: usize = 64;
+
+// One bit per 4 KiB frame, set when the frame is used or unusable, and a
+// reference count per frame so frames can be shared between mappings
+pub struct FrameAlloc {
+    bitmap: &'static mut [u64],
+    refs: &'static mut [u16],
+    usable: usize,
+    used: usize,
+    next: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn total_bytes(&self) -> usize {
+        self.total * FRAME_SIZE
+    }
+    pub fn used_bytes(&self) -> usize {
+        self.used * FRAME_SIZE
+    }
+    pub fn free_bytes(&self) -> usize {
+        self.free * FRAME_SIZE
+    }
+}
+
+impl FrameAlloc {
+    /// # Safety
+    /// The memory map has to be valid and all physical memory has to be mapped at `phys_offset`
+    pub unsafe fn new(memory_map: &MemoryMap, phys_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let frames = usable_regions()
+            .map(|r| r.range.end_frame_number as usize)
+            .max()
+            .unwrap_or(0);
+        let words = (frames + BITS - 1) / BITS;
+        let refs_offset = words * 8;
+        let bitmap_frames = (refs_offset + words * BITS * 2 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // The bitmap and reference counts live in the first usable region large enough to hold them
+        let bitmap_region = usable_regions()
+            .find(|r| {
+                (r.range.end_frame_number - r.range.start_frame_number) as usize >= bitmap_frames
+            })
+            .expect("no usable memory region can hold the frame bitmap");
+        let bitmap_virt = phys_offset + bitmap_region.range.start_addr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_virt.as_mut_ptr::<u64>(), words);
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let refs = slice::from_raw_parts_mut(
+            (bitmap_virt + refs_offset).as_mut_ptr::<u16>(),
+            words * BITS,
+        );
+        for count in refs.iter_mut() {
+            *count = 0;
+        }
+
+        let mut allocator = Self {
+            bitmap,
+            refs,
+            usable: 0,
+            used: 0,
+            next: 0,
+        };
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let count = region.range.end_frame_number as usize - start;
+            allocator.set(start, count, false);
+            allocator.usable += count;
+        }
+        allocator.mark(
+            bitmap_region.range.start_frame_number as usize,
+            bitmap_frames,
+            true,
+        );
+
+        allocator
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let idx = (self.next + i) % words;
+            let word = self.bitmap[idx];
+            if word != !0 {
+                let frame = idx * BITS + (!word).trailing_zeros() as usize;
+                self.mark(frame, 1, true);
+                self.refs[frame] = 1;
+                self.next = idx;
+                return Some(frame_at(frame));
+            }
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames, the first one being aligned to `align` frames
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two());
+        if count == 0 {
+            return None;
+        }
+
+        let frames = self.bitmap.len() * BITS;
+        let mut start = 0;
+        while start + count <= frames {
+            match (start..start + count).rev().find(|&f| self.is_used(f)) {
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    self.mark(start, count, true);
+                    for refs in &mut self.refs[start..start + count] {
+                        *refs = 1;
+                    }
+                    return Some(frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Drops a reference to the frame, freeing it once there are none left
+    ///
+    /// # Safety
+    /// The frame has to have been allocated by this allocator and the reference can't be in use anymore
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1)
+    }
+
+    /// # Safety
+    /// The frames have to have been allocated by this allocator and the references can't be in use anymore
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = frame_index(start);
+        for frame in first..first + count {
+            assert!(
+                self.is_used(frame),
+                "physical frame {:#x} deallocated while free",
+                frame * FRAME_SIZE,
+            );
+            // Frames the bootloader allocated have no count yet
+            if self.refs[frame] > 1 {
+                self.refs[frame] -= 1;
+            } else {
+                self.refs[frame] = 0;
+                self.mark(frame, 1, false);
+            }
+        }
+        self.next = self.next.min(first / BITS);
+    }
+
+    /// Adds a reference to an allocated frame, which then takes one more `deallocate` to free
+    pub fn share(&mut self, frame: PhysFrame) {
+        let idx = frame_index(frame);
+        assert!(
+            self.is_used(idx),
+            "physical frame {:#x} shared while free",
+            idx * FRAME_SIZE,
+        );
+        self.refs[idx] = self.refs[idx]
+            .max(1)
+            .checked_add(1)
+            .expect("too many frame references");
+    }
+
+    /// Number of references to the frame, 0 if it's free
+    pub fn refs(&self, frame: PhysFrame) -> usize {
+        let idx = frame_index(frame);
+        if self.is_used(idx) {
+            self.refs[idx].max(1) as usize
+        } else {
+            0
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.usable,
+            used: self.used,
+            free: self.usable - self.used,
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn mark(&mut self, start: usize, count: usize, used: bool) {
+        self.set(start, count, used);
+        if used {
+            self.used += count;
+        } else {
+            self.used -= count;
+        }
+    }
+
+    fn set(&mut self, start: usize, count: usize, used: bool) {
+        for frame in start..start + count {
+            let word = &mut self.bitmap[frame / BITS];
+            let bit = 1 << (frame % BITS);
+            if used {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+}
+
+fn frame_at(idx: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new((idx * FRAME_SIZE) as u64))
+}
+
+fn frame_index<S: PageSize>(frame: PhysFrame<S>) -> usize {
+    frame.start_address().as_u64() as usize / FRAME_SIZE
+}
+
+const FRAMES_PER_2MIB: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+const FRAMES_PER_1GIB: usize = (Size1GiB::SIZE / Size4KiB::SIZE) as usize;
+
+unsafe impl FrameAllocator<Size4KiB> for FrameAlloc {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate()
+    }
+}
+unsafe impl FrameAllocator<Size2MiB> for FrameAlloc {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_contiguous(FRAMES_PER_2MIB, FRAMES_PER_2MIB)
+            .map(|f| PhysFrame::containing_address(f.start_address()))
+    }
+}
+unsafe impl FrameAllocator<Size1GiB> for FrameAlloc {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_contiguous(FRAMES_PER_1GIB, FRAMES_PER_1GIB)
+            .map(|f| PhysFrame::containing_address(f.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FrameAlloc {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame)
+    }
+}
+impl FrameDeallocator<Size2MiB> for FrameAlloc {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_contiguous(
+            PhysFrame::containing_address(frame.start_address()),
+            FRAMES_PER_2MIB,
+        )
+    }
+}
+impl FrameDeallocator<Size1GiB> for FrameAlloc {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate_contiguous(
+            PhysFrame::containing_address(frame.start_address()),
+            FRAMES_PER_1GIB,
+        )
+    }
+}
@@ -0,0 +1,199 @@
+use super::vm::{self, RegionKind, VmError};
+use core::{
+    arch::x86_64::__cpuid,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory types for PAT entries 0 to 7, the power-on defaults except for entry 1 which is
+// write-combining instead of write-through, so it's selected by PWT alone. Entry 7 is
+// reached through bit 7, which the page table code would take for a huge page.
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+const PAT: [u64; 8] = [WB, WC, UC_MINUS, UC, WB, WT, UC_MINUS, UC];
+
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Strongly ordered and never cached, for device registers
+    Uncached,
+    /// Writes are buffered and merged, for framebuffers. Uncached if the CPU has no PAT
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Page table flags selecting the PAT entry for the mode
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteCombining if write_combining() => PageTableFlags::WRITE_THROUGH,
+            _ => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the PAT so write-combining mappings are possible
+pub fn init() {
+    let cpuid = unsafe { __cpuid(1) };
+    if cpuid.edx & (1 << 16) == 0 {
+        return;
+    }
+
+    let pat = PAT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, &ty)| pat | ty << (i * 8));
+    unsafe {
+        Msr::new(IA32_PAT).write(pat);
+        // Nothing should be cached under the type entry 1 had before
+        asm!("wbinvd", options(nostack));
+    }
+    tlb::flush_all();
+    WRITE_COMBINING.store(true, Ordering::SeqCst);
+}
+
+/// Whether `CacheMode::WriteCombining` really combines writes
+pub fn write_combining() -> bool {
+    WRITE_COMBINING.load(Ordering::SeqCst)
+}
+
+/// Device memory laid out as a `T`, unmapped on drop
+///
+/// `T` is usually a `#[repr(C)]` struct of `Volatile` registers.
+pub struct Mmio<T> {
+    region: VirtAddr,
+    addr: VirtAddr,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Mmio<T> {
+    /// Maps `size_of::<T>()` bytes starting at `phys`
+    ///
+    /// # Safety
+    /// The physical range has to belong to a device, mapping RAM uncached aliases it
+    pub unsafe fn map(phys: PhysAddr, cache: CacheMode) -> Result<Self, VmError> {
+        let size = mem::size_of::<T>().max(1) as u64;
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let end_frame = PhysFrame::<Size4KiB>::containing_address(phys + (size - 1));
+        let len = end_frame.start_address() - start_frame.start_address() + Size4KiB::SIZE;
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+        let region = vm::reserve(len, 0, RegionKind::Mmio, flags, false)?;
+        if let Err(err) = vm::map_phys(region.start, start_frame.start_address(), len) {
+            vm::free(region.start)?;
+            return Err(err);
+        }
+
+        Ok(Self {
+            region: region.start,
+            addr: region.start + phys.as_u64() % Size4KiB::SIZE,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Keeps the mapping forever
+    pub fn leak(self) -> &'static mut T {
+        let addr = self.addr;
+        mem::forget(self);
+        unsafe { &mut *addr.as_mut_ptr() }
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.addr.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for Mmio<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.addr.as_mut_ptr() }
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        unsafe { vm::free(self.region).expect("MMIO region vanished") };
+    }
+}
+
+// Only the mapping is owned, access goes through the registers in `T`
+unsafe impl<T: Send> Send for Mmio<T> {}
+unsafe impl<T: Sync> Sync for Mmio<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheMode, Mmio};
+    use crate::{
+        interrupts::apic::LOCAL_APIC,
+        mem::{paging, vm, Volatile},
+    };
+    use x86_64::{
+        registers::model_specific::Msr, structures::paging::PageTableFlags, PhysAddr, VirtAddr,
+    };
+
+    // Index of the PAT entry selected by the PAT, PCD and PWT bits of a 4 KiB page
+    fn pat_index(flags: PageTableFlags) -> u64 {
+        let bit = |flag, shift: u64| if flags.contains(flag) { 1 << shift } else { 0 };
+        bit(PageTableFlags::HUGE_PAGE, 2)
+            | bit(PageTableFlags::NO_CACHE, 1)
+            | bit(PageTableFlags::WRITE_THROUGH, 0)
+    }
+
+    #[test_case]
+    fn pat() {
+        let entry = |flags| {
+            let pat = unsafe { Msr::new(super::IA32_PAT).read() };
+            (pat >> (pat_index(flags) * 8)) & 0xFF
+        };
+        assert_eq!(entry(CacheMode::Uncached.flags()), super::UC);
+        if super::write_combining() {
+            assert_eq!(pat_index(CacheMode::WriteCombining.flags()), 1);
+            assert_eq!(entry(CacheMode::WriteCombining.flags()), super::WC);
+        }
+    }
+
+    #[test_case]
+    fn vga_write_combining() {
+        let flags = paging::flags(VirtAddr::new(0xB8000)).unwrap();
+        if super::write_combining() {
+            assert_eq!(pat_index(flags), 1);
+        }
+    }
+
+    #[test_case]
+    fn unmap_on_drop() {
+        let local = match LOCAL_APIC.try_get() {
+            Some(local) => local,
+            None => return,
+        };
+        let base = unsafe { Msr::new(0x1B).read() } & 0x000F_FFFF_FFFF_F000;
+        // Just the ID register
+        let id = PhysAddr::new(base + 0x20);
+        let id = unsafe { Mmio::<Volatile<u32>>::map(id, CacheMode::Uncached) }.unwrap();
+        let addr = id.addr();
+
+        assert_eq!((id.read() >> 24) as u8, local.id());
+        drop(id);
+        assert_eq!(vm::find(addr), None);
+    }
+}
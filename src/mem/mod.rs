@@ -1,5 +1,41 @@
 pub mod alloc;
+pub mod frame;
+pub mod mmio;
 pub mod paging;
+pub mod stack;
+pub mod vm;
 pub mod volatile;
 
 pub use volatile::Volatile;
+
+use crate::sync::IrqMutex;
+use bootloader::BootInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+pub fn init(boot_info: &'static BootInfo) {
+    mmio::init();
+
+    let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { paging::mapper(phys_offset) };
+    let frame_allocator = unsafe { paging::frame_allocator(&boot_info.memory_map, phys_offset) };
+
+    paging::PHYS_OFFSET.init_once(|| phys_offset);
+    paging::MAPPER.init_once(|| IrqMutex::new(mapper));
+    frame::FRAMES.init_once(|| IrqMutex::new(frame_allocator));
+    paging::coalesce_physical_memory();
+
+    alloc::init_heap().expect("heap initialization failed");
+
+    // Identity mapped by the bootloader, then switched to write-combining
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let vga = vm::reserve_at(
+        VirtAddr::new(0xB8000),
+        0x1000,
+        vm::RegionKind::Framebuffer,
+        flags,
+        false,
+    )
+    .expect("VGA buffer region taken");
+    let flags = flags | mmio::CacheMode::WriteCombining.flags();
+    vm::protect(vga.start, vga.size(), flags).expect("VGA buffer remapping failed");
+}
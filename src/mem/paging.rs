@@ -1,13 +1,26 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use super::frame::{FrameAlloc, FRAMES};
+use crate::sync::{IrqMutex, Once};
+use bootloader::bootinfo::MemoryMap;
+use core::arch::x86_64::__cpuid;
 use x86_64::{
+    instructions::tlb,
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, Mapper, OffsetPageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::MapToError, OffsetPageTable, Page, PageSize, PageTable, PageTableEntry,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+pub static PHYS_OFFSET: Once<VirtAddr> = Once::new();
+pub static MAPPER: Once<IrqMutex<OffsetPageTable<'static>>> = Once::new();
+
+/// Set on read-only entries whose frame is shared and gets copied on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
 /// # Safety
 /// An invalid offset will just completely fuck up paging
-pub unsafe fn mapper(phys_offset: VirtAddr) -> impl Mapper<Size4KiB> + 'static {
+pub unsafe fn mapper(phys_offset: VirtAddr) -> OffsetPageTable<'static> {
     let (frame, _) = Cr3::read();
 
     let phys = frame.start_address();
@@ -18,33 +31,434 @@ pub unsafe fn mapper(phys_offset: VirtAddr) -> impl Mapper<Size4KiB> + 'static {
 }
 
 /// # Safety
-/// An invalid memory map will just completely fuck up paging
-pub unsafe fn frame_allocator(memory_map: &'static MemoryMap) -> impl FrameAllocator<Size4KiB> {
-    BootInfoFrameAllocator {
-        memory_map,
-        next: 0,
+/// An invalid memory map or offset will just completely fuck up paging
+pub unsafe fn frame_allocator(memory_map: &MemoryMap, phys_offset: VirtAddr) -> FrameAlloc {
+    FrameAlloc::new(memory_map, phys_offset)
+}
+
+/// Whether 1 GiB pages can be used, 2 MiB pages always can in long mode
+pub fn gigantic_pages() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/// Size of the page mapping `addr` in the active address space
+pub fn mapping_size(addr: VirtAddr) -> Option<u64> {
+    let (l4, _) = Cr3::read();
+    unsafe { leaf(l4, addr) }.map(|(_, level)| level_size(level))
+}
+
+/// Whether nothing is mapped in the `size` aligned block at `addr`, so a page of that size fits
+pub fn block_free(addr: VirtAddr, size: u64) -> bool {
+    let level = match size {
+        Size1GiB::SIZE => 3,
+        Size2MiB::SIZE => 2,
+        _ => 1,
+    };
+    let (l4, _) = Cr3::read();
+    match unsafe { entry(l4, addr, level) } {
+        Some(entry) => entry.is_unused(),
+        // Either a table above doesn't exist yet or a bigger page maps the block
+        None => unsafe { leaf(l4, addr) }.is_none(),
+    }
+}
+
+/// Flags of the page mapping `addr` in the active address space
+pub fn flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let (l4, _) = Cr3::read();
+    unsafe { leaf(l4, addr) }.map(|(entry, _)| entry.flags())
+}
+
+/// Breaks the huge page mapping `addr` into pages one size smaller, mapping the same frames
+///
+/// The caller has to hold the `MAPPER` lock.
+pub fn split(addr: VirtAddr, frames: &mut FrameAlloc) -> Result<(), MapToError<Size4KiB>> {
+    let (l4, _) = Cr3::read();
+    let (entry, level) = match unsafe { leaf(l4, addr) } {
+        Some((entry, level)) if level != 1 => (entry, level),
+        _ => return Ok(()),
+    };
+
+    let table = allocate_table(frames)?;
+    let huge = entry.flags();
+    let child_flags = if level == 2 {
+        huge - PageTableFlags::HUGE_PAGE
+    } else {
+        huge
+    };
+    let child_size = level_size(level - 1);
+    unsafe {
+        let children = &mut *table_ptr::<PageTable>(table);
+        for (i, child) in children.iter_mut().enumerate() {
+            child.set_addr(entry.addr() + i as u64 * child_size, child_flags);
+        }
+    }
+
+    // The children have the exact permissions, the table entry allows everything they might
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (huge & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_frame(table, table_flags);
+    tlb::flush_all();
+
+    Ok(())
+}
+
+/// Remaps the physical memory window with 1 GiB pages where the bootloader used 2 MiB ones
+///
+/// Returns how many gigabytes were remapped. The bootloader's level 2 tables are
+/// left alone since they aren't from the frame allocator.
+pub fn coalesce_physical_memory() -> usize {
+    let phys_offset = *PHYS_OFFSET.try_get().expect("paging not initialized");
+    if !gigantic_pages() || !phys_offset.is_aligned(Size1GiB::SIZE) {
+        return 0;
+    }
+
+    let _mapper = MAPPER.try_get().expect("paging not initialized").lock();
+    let (l4, _) = Cr3::read();
+    let mut coalesced = 0;
+    for gib in 0.. {
+        let phys = PhysAddr::new(gib * Size1GiB::SIZE);
+        let entry = match unsafe { entry(l4, phys_offset + phys.as_u64(), 3) } {
+            Some(entry) if !entry.is_unused() => entry,
+            _ => break,
+        };
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        let table =
+            unsafe { &*table_ptr::<PageTable>(PhysFrame::containing_address(entry.addr())) };
+        let flags = table[0].flags();
+        let contiguous = table
+            .iter()
+            .enumerate()
+            .all(|(i, e)| e.flags() == flags && e.addr() == phys + i as u64 * Size2MiB::SIZE);
+        if !contiguous || !flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        entry.set_addr(phys, flags);
+        coalesced += 1;
+    }
+    tlb::flush_all();
+
+    coalesced
+}
+
+/// Gives the page at `addr` its own writable frame if it's copy-on-write in the active address space
+///
+/// Returns whether it was. The caller has to hold the `MAPPER` lock.
+pub fn copy_on_write(
+    addr: VirtAddr,
+    frames: &mut FrameAlloc,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let (l4, _) = Cr3::read();
+    let entry = match unsafe { leaf(l4, addr) } {
+        Some((entry, 1)) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return Ok(false),
+    };
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if frames.refs(frame) > 1 {
+        let copy = frames.allocate().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                table_ptr::<u8>(frame),
+                table_ptr::<u8>(copy),
+                Size4KiB::SIZE as usize,
+            );
+            entry.set_frame(copy, flags);
+            frames.deallocate(frame);
+        }
+    } else {
+        entry.set_flags(flags);
+    }
+    tlb::flush(addr);
+
+    Ok(true)
+}
+
+/// Page table hierarchy rooted at a level 4 table
+///
+/// Only entries with `USER_ACCESSIBLE` set belong to the address space, the others
+/// are the kernel's and shared by every address space. Kernel mappings are only
+/// visible everywhere if their level 4 entry existed when the address space was forked.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l4: PhysFrame,
+    owned: bool,
+}
+
+impl AddressSpace {
+    /// The active address space, which is never freed through this handle
+    pub fn current() -> Self {
+        Self {
+            l4: Cr3::read().0,
+            owned: false,
+        }
+    }
+
+    pub fn l4(&self) -> PhysFrame {
+        self.l4
+    }
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// # Safety
+    /// Nothing else can modify the page tables while the mapper is used
+    pub unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let phys_offset = *PHYS_OFFSET.try_get().expect("paging not initialized");
+        OffsetPageTable::new(&mut *table_ptr(self.l4), phys_offset)
     }
+
+    /// # Safety
+    /// The kernel has to be mapped the same way in both address spaces, which is
+    /// the case for forks of the active one
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.l4, flags);
+    }
+
+    /// Clones the address space, sharing the frames of user mappings copy-on-write
+    ///
+    /// Writable user pages become read-only in both address spaces until written to.
+    /// User huge pages can't be forked yet.
+    pub fn fork(&self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let (child, result) = {
+            let _mapper = MAPPER.try_get().expect("paging not initialized").lock();
+            let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+
+            let child = AddressSpace {
+                l4: allocate_table(&mut frames)?,
+                owned: true,
+            };
+            let result = unsafe { fork_table(self.l4, child.l4, 4, &mut frames) };
+            if self.is_active() {
+                tlb::flush_all();
+            }
+            (child, result)
+        };
+
+        // Dropping a partial fork frees what was copied so far, which takes the locks again
+        result.map(|()| child)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        assert!(!self.is_active(), "dropped the active address space");
+
+        let _mapper = MAPPER.try_get().expect("paging not initialized").lock();
+        let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+        unsafe { free_table(self.l4, 4, &mut frames) };
+    }
+}
+
+unsafe fn fork_table(
+    src: PhysFrame,
+    dst: PhysFrame,
+    level: u8,
+    frames: &mut FrameAlloc,
+) -> Result<(), MapToError<Size4KiB>> {
+    let src = &mut *table_ptr::<PageTable>(src);
+    let dst = &mut *table_ptr::<PageTable>(dst);
+
+    for (entry, copy) in src.iter_mut().zip(dst.iter_mut()) {
+        let flags = entry.flags();
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 4 && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            *copy = entry.clone();
+            continue;
+        }
+        if level != 1 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapToError::ParentEntryHugePage);
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                entry.set_flags((flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE);
+            }
+            frames.share(frame);
+            *copy = entry.clone();
+        } else {
+            let table = allocate_table(frames)?;
+            copy.set_frame(table, flags);
+            fork_table(frame, table, level - 1, frames)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Drops the references the address space holds to user frames and page tables
+unsafe fn free_table(table: PhysFrame, level: u8, frames: &mut FrameAlloc) {
+    let entries = &mut *table_ptr::<PageTable>(table);
+    for entry in entries.iter_mut() {
+        let flags = entry.flags();
+        if entry.is_unused() || level == 4 && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            continue;
+        }
+        let frame = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            frames.deallocate(frame);
+        } else if flags.contains(PageTableFlags::HUGE_PAGE) {
+            let count = level_size(level - 1) / Size4KiB::SIZE;
+            frames.deallocate_contiguous(frame, count as usize);
+        } else {
+            free_table(frame, level - 1, frames);
+            frames.deallocate(frame);
+        }
+        entry.set_unused();
+    }
+    if level == 4 {
+        frames.deallocate(table);
+    }
+}
+
+fn allocate_table(frames: &mut FrameAlloc) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = frames.allocate().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe { (*table_ptr::<PageTable>(frame)).zero() };
+    Ok(frame)
+}
+
+// The entry at `level` on the way to `addr`, if the tables above it exist
+unsafe fn entry(l4: PhysFrame, addr: VirtAddr, level: u8) -> Option<&'static mut PageTableEntry> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let indices = [
+        page.p4_index(),
+        page.p3_index(),
+        page.p2_index(),
+        page.p1_index(),
+    ];
+
+    let mut table = &mut *table_ptr::<PageTable>(l4);
+    for &index in &indices[..4 - level as usize] {
+        let entry = &table[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *table_ptr::<PageTable>(PhysFrame::containing_address(entry.addr()));
+    }
+    Some(&mut table[indices[4 - level as usize]])
 }
 
-struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+// The entry mapping `addr` and its level, 1 for 4 KiB pages up to 3 for 1 GiB ones
+unsafe fn leaf(l4: PhysFrame, addr: VirtAddr) -> Option<(&'static mut PageTableEntry, u8)> {
+    for level in (1..=3).rev() {
+        let entry = entry(l4, addr, level)?;
+        if entry.is_unused() {
+            return None;
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some((entry, level));
+        }
+    }
+    None
 }
 
-impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+fn level_size(level: u8) -> u64 {
+    match level {
+        1 => Size4KiB::SIZE,
+        2 => Size2MiB::SIZE,
+        _ => Size1GiB::SIZE,
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+fn table_ptr<T>(frame: PhysFrame) -> *mut T {
+    let phys_offset = PHYS_OFFSET.try_get().expect("paging not initialized");
+    (*phys_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressSpace, COPY_ON_WRITE, FRAMES};
+    use x86_64::{
+        structures::paging::{
+            Mapper, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+        },
+        VirtAddr,
+    };
+
+    #[test_case]
+    fn fork() {
+        let current = AddressSpace::current();
+        let child = current.fork().unwrap();
+        assert_ne!(child.l4(), current.l4());
+
+        let parent = unsafe { &*super::table_ptr::<PageTable>(current.l4()) };
+        let copy = unsafe { &*super::table_ptr::<PageTable>(child.l4()) };
+        for (entry, copy) in parent.iter().zip(copy.iter()) {
+            if !entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                assert_eq!(entry.addr(), copy.addr());
+            }
+        }
+    }
+
+    #[test_case]
+    fn fork_shares_user_frames() {
+        // A user page in a lower half slot nothing else uses, in a copy so the active
+        // address space isn't touched
+        let mut parent = AddressSpace::current().fork().unwrap();
+        let l4 = unsafe { &*super::table_ptr::<PageTable>(parent.l4()) };
+        let slot = (1..256).find(|&i| l4[i].is_unused()).unwrap();
+        let addr = VirtAddr::new((slot as u64) << 39);
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE;
+        let frame = {
+            let mut frames = FRAMES.try_get().unwrap().lock();
+            let frame: PhysFrame = frames.allocate().unwrap();
+            let page = Page::containing_address(addr);
+            unsafe {
+                parent
+                    .mapper()
+                    .map_to(page, frame, flags, &mut *frames)
+                    .unwrap()
+                    .ignore();
+                // Only user entries of the level 4 table are forked
+                for level in 2..=4 {
+                    let entry = super::entry(parent.l4(), addr, level).unwrap();
+                    entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+                }
+            }
+            frame
+        };
+
+        let child = parent.fork().unwrap();
+        let refs = || FRAMES.try_get().unwrap().lock().refs(frame);
+        assert_eq!(refs(), 2);
+        for space in &[&parent, &child] {
+            let (entry, level) = unsafe { super::leaf(space.l4(), addr) }.unwrap();
+            assert_eq!(level, 1);
+            assert_eq!(entry.addr(), frame.start_address());
+            assert!(!entry.flags().contains(PageTableFlags::WRITABLE));
+            assert!(entry.flags().contains(COPY_ON_WRITE));
+        }
+
+        drop(child);
+        assert_eq!(refs(), 1);
+        drop(parent);
+        assert_eq!(refs(), 0);
+    }
+
+    #[test_case]
+    fn physical_memory() {
+        let phys_offset = *super::PHYS_OFFSET.try_get().unwrap();
+        let size = super::mapping_size(phys_offset).unwrap();
+        if super::gigantic_pages() && phys_offset.is_aligned(Size1GiB::SIZE) {
+            assert_eq!(size, Size1GiB::SIZE);
+        } else {
+            assert!(size >= Size2MiB::SIZE);
+        }
     }
 }
@@ -0,0 +1,76 @@
+use super::vm::{self, RegionKind, VmError};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const GUARD_PAGES: u64 = 1;
+
+/// Kernel stack with an unmapped guard page below it, unmapped on drop
+#[derive(Debug)]
+pub struct Stack {
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// The size is rounded up to whole pages
+    pub fn allocate(size: usize) -> Result<Self, VmError> {
+        Self::reserve(size, size)
+    }
+
+    /// Maps only the top `committed` bytes, the rest is mapped on first touch
+    pub fn reserve(size: usize, committed: usize) -> Result<Self, VmError> {
+        let size = align_up(size as u64);
+        let committed = align_up(committed as u64).min(size);
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let region = vm::reserve(
+            size,
+            GUARD_PAGES * Size4KiB::SIZE,
+            RegionKind::Stack,
+            flags,
+            committed < size,
+        )?;
+        let stack = Self {
+            bottom: region.start,
+            top: region.end,
+        };
+
+        // On failure the stack is dropped, which unmaps the pages mapped so far
+        vm::map(stack.top - committed, committed)?;
+        Ok(stack)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    /// Keeps the stack mapped forever and returns its top
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top;
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { vm::free(self.bottom).expect("stack region vanished") };
+    }
+}
+
+/// Whether a non-present fault at `addr` hit a guard page
+pub fn is_guard(addr: VirtAddr) -> bool {
+    vm::is_guard(addr)
+}
+
+fn align_up(size: u64) -> u64 {
+    (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1)
+}
@@ -0,0 +1,933 @@
+use super::{
+    frame::{FrameAlloc, FRAMES},
+    paging::{self, COPY_ON_WRITE, MAPPER, PHYS_OFFSET},
+};
+use crate::{
+    sync::{mutex::IrqMutexGuard, IrqMutex},
+    thread,
+};
+use core::fmt;
+use x86_64::{
+    instructions::tlb,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper,
+            OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+            Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Kernel virtual memory handed out by `reserve`
+pub const VM_START: u64 = 0x4000_0000_0000;
+pub const VM_END: u64 = 0x8000_0000_0000;
+pub const MAX_REGIONS: usize = 128;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+// A fixed table so the page fault handler never needs the allocator
+static REGIONS: IrqMutex<[Option<Region>; MAX_REGIONS]> = IrqMutex::new([None; MAX_REGIONS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Anonymous,
+    Mmio,
+    Framebuffer,
+}
+
+impl RegionKind {
+    /// Whether the frames mapped in the region come from the frame allocator
+    pub fn owns_frames(self) -> bool {
+        !matches!(self, RegionKind::Mmio | RegionKind::Framebuffer)
+    }
+}
+
+/// Range of kernel virtual memory, which gets zeroed frames mapped on first access if `demand` is set
+///
+/// The `guard` bytes below `start` are never mapped or handed out. Regions split by
+/// `protect` keep the `base` of the reservation they came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub guard: u64,
+    pub base: VirtAddr,
+    pub kind: RegionKind,
+    pub flags: PageTableFlags,
+    pub demand: bool,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    fn in_guard(&self, addr: VirtAddr) -> bool {
+        self.start - self.guard <= addr && addr < self.start
+    }
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start - self.guard < end && start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    Overlap(Region),
+    /// The region table is full
+    Full,
+    /// No free range of virtual addresses is large enough
+    OutOfSpace,
+    /// The range isn't inside a single reserved region
+    NotReserved,
+    /// The operation doesn't work on regions of this kind
+    WrongKind(RegionKind),
+    OutOfMemory,
+    AlreadyMapped,
+    HugePage,
+}
+
+impl From<MapToError<Size4KiB>> for VmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) => VmError::AlreadyMapped,
+            MapToError::ParentEntryHugePage => VmError::HugePage,
+        }
+    }
+}
+
+/// Picks a free range between `VM_START` and `VM_END` for a new region
+///
+/// Regions of 2 MiB or more are aligned so they can use huge pages. Nothing is
+/// mapped yet, see `map` and `map_phys`.
+pub fn reserve(
+    size: u64,
+    guard: u64,
+    kind: RegionKind,
+    flags: PageTableFlags,
+    demand: bool,
+) -> Result<Region, VmError> {
+    let size = align_up(size.max(1));
+    let guard = align_up(guard);
+    let align = if size >= Size1GiB::SIZE && paging::gigantic_pages() {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    };
+
+    let mut regions = REGIONS.lock();
+    let mut candidate = VM_START;
+    let (start, end) = loop {
+        let start = align_to(candidate + guard, align);
+        let end = start + size;
+        if end > VM_END {
+            return Err(VmError::OutOfSpace);
+        }
+        match regions
+            .iter()
+            .flatten()
+            .find(|r| r.overlaps(VirtAddr::new(start - guard), VirtAddr::new(end)))
+        {
+            Some(other) => candidate = other.end.as_u64(),
+            None => break (VirtAddr::new(start), VirtAddr::new(end)),
+        }
+    };
+
+    insert(
+        &mut regions[..],
+        Region {
+            start,
+            end,
+            guard,
+            base: start,
+            kind,
+            flags: flags | PageTableFlags::PRESENT,
+            demand,
+        },
+    )
+}
+
+/// Registers a region at a fixed address, like ones the bootloader mapped
+pub fn reserve_at(
+    start: VirtAddr,
+    size: u64,
+    kind: RegionKind,
+    flags: PageTableFlags,
+    demand: bool,
+) -> Result<Region, VmError> {
+    let start = start.align_down(PAGE_SIZE);
+    let region = Region {
+        start,
+        end: start + align_up(size.max(1)),
+        guard: 0,
+        base: start,
+        kind,
+        flags: flags | PageTableFlags::PRESENT,
+        demand,
+    };
+
+    let mut regions = REGIONS.lock();
+    if let Some(other) = regions
+        .iter()
+        .flatten()
+        .find(|r| r.overlaps(region.start, region.end))
+    {
+        return Err(VmError::Overlap(*other));
+    }
+    insert(&mut regions[..], region)
+}
+
+fn insert(regions: &mut [Option<Region>], region: Region) -> Result<Region, VmError> {
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(VmError::Full)?;
+    *slot = Some(region);
+    Ok(region)
+}
+
+/// Forgets the region starting at `start`, leaving its pages mapped
+pub fn release(start: VirtAddr) -> Option<Region> {
+    REGIONS
+        .lock()
+        .iter_mut()
+        .find(|r| r.map_or(false, |r| r.start == start))?
+        .take()
+}
+
+/// Unmaps the reservation containing `start` and forgets it, along with the pieces
+/// `protect` split off of it
+///
+/// # Safety
+/// Nothing can use the reservation anymore
+pub unsafe fn free(start: VirtAddr) -> Result<(), VmError> {
+    let base = find(start).ok_or(VmError::NotReserved)?.base;
+    while let Some(region) = piece_of(base) {
+        unmap(region.start, region.size())?;
+        release(region.start);
+    }
+    Ok(())
+}
+
+// Any region left of the reservation at `base`
+fn piece_of(base: VirtAddr) -> Option<Region> {
+    REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|r| r.base == base)
+        .copied()
+}
+
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+}
+
+// The region containing all of `[start, start + size)`
+fn containing(start: VirtAddr, size: u64) -> Result<Region, VmError> {
+    find(start)
+        .filter(|r| start + size <= r.end)
+        .ok_or(VmError::NotReserved)
+}
+
+/// Backs a reserved range with zeroed frames, using the flags of its region
+///
+/// Aligned parts are mapped with huge pages while there are large frames left.
+pub fn map(start: VirtAddr, size: u64) -> Result<(), VmError> {
+    let region = containing(start, size)?;
+    let start = start.align_down(PAGE_SIZE);
+    let end = start + align_up(size);
+
+    let mut mapper = MAPPER.try_get().expect("paging not initialized").lock();
+    let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+
+    let mut addr = start;
+    while addr < end {
+        match map_next(&mut mapper, &mut frames, addr, end, region.flags) {
+            Ok(size) => addr += size,
+            Err(err) => {
+                // Leave the range unmapped so mapping it can be retried
+                unsafe { unmap_pages(&mut mapper, &mut frames, start, addr - start, true)? };
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Maps the biggest page which fits at `addr`, returning its size
+fn map_next(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut FrameAlloc,
+    addr: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<u64, VmError> {
+    if paging::gigantic_pages() && map_huge::<Size1GiB>(mapper, frames, addr, end, flags) {
+        return Ok(Size1GiB::SIZE);
+    }
+    if map_huge::<Size2MiB>(mapper, frames, addr, end, flags) {
+        return Ok(Size2MiB::SIZE);
+    }
+
+    let frame = frames.allocate().ok_or(VmError::OutOfMemory)?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    unsafe {
+        zero(frame.start_address(), PAGE_SIZE);
+        match mapper.map_to(page, frame, flags, frames) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                frames.deallocate(frame);
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(PAGE_SIZE)
+}
+
+// Maps a zeroed `S` page at `addr` if it's aligned, fits before `end`, nothing is mapped
+// there yet and there's a large enough frame
+fn map_huge<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut FrameAlloc,
+    addr: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+) -> bool
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    FrameAlloc: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    if !addr.is_aligned(S::SIZE) || end - addr < S::SIZE || !paging::block_free(addr, S::SIZE) {
+        return false;
+    }
+    let frame = match FrameAllocator::<S>::allocate_frame(frames) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let page = Page::<S>::containing_address(addr);
+    unsafe {
+        zero(frame.start_address(), S::SIZE);
+        match Mapper::<S>::map_to(
+            mapper,
+            page,
+            frame,
+            flags | PageTableFlags::HUGE_PAGE,
+            frames,
+        ) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                FrameDeallocator::<S>::deallocate_frame(frames, frame);
+                false
+            }
+        }
+    }
+}
+
+/// Maps a reserved range to the physical range starting at `phys`
+///
+/// # Safety
+/// The physical range can't be in use as anything else than what the region is for
+pub unsafe fn map_phys(start: VirtAddr, phys: PhysAddr, size: u64) -> Result<(), VmError> {
+    let region = containing(start, size)?;
+
+    let mut mapper = MAPPER.try_get().expect("paging not initialized").lock();
+    let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    for (i, page) in pages(start, size).enumerate() {
+        let frame = first + i as u64;
+        match mapper.map_to(page, frame, region.flags, &mut *frames) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // The frames are the device's, only the mappings are undone
+                let mapped = page.start_address() - start.align_down(PAGE_SIZE);
+                unmap_pages(&mut mapper, &mut frames, start, mapped, false)?;
+                return Err(err.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmaps whatever is mapped in a reserved range, freeing frames the region owns
+///
+/// # Safety
+/// Nothing can use the range anymore
+pub unsafe fn unmap(start: VirtAddr, size: u64) -> Result<(), VmError> {
+    let region = containing(start, size)?;
+
+    let mut mapper = MAPPER.try_get().expect("paging not initialized").lock();
+    let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+
+    unmap_pages(
+        &mut mapper,
+        &mut frames,
+        start,
+        size,
+        region.kind.owns_frames(),
+    )
+}
+
+// The caller has to hold the `MAPPER` lock
+unsafe fn unmap_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut FrameAlloc,
+    start: VirtAddr,
+    size: u64,
+    owned: bool,
+) -> Result<(), VmError> {
+    for_each_page(frames, start, size, |frames, addr, size| match size {
+        Size1GiB::SIZE => unmap_page::<Size1GiB>(mapper, frames, addr, owned),
+        Size2MiB::SIZE => unmap_page::<Size2MiB>(mapper, frames, addr, owned),
+        _ => unmap_page::<Size4KiB>(mapper, frames, addr, owned),
+    })
+}
+
+unsafe fn unmap_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut FrameAlloc,
+    addr: VirtAddr,
+    owned: bool,
+) where
+    OffsetPageTable<'static>: Mapper<S>,
+    FrameAlloc: FrameDeallocator<S>,
+{
+    if let Ok((frame, flush)) = Mapper::<S>::unmap(mapper, Page::containing_address(addr)) {
+        flush.flush();
+        if owned {
+            FrameDeallocator::<S>::deallocate_frame(frames, frame);
+        }
+    }
+}
+
+// Calls `f` with every mapped page in the range and its size, after splitting huge
+// pages which stick out of it. The caller has to hold the `MAPPER` lock.
+fn for_each_page(
+    frames: &mut FrameAlloc,
+    start: VirtAddr,
+    size: u64,
+    mut f: impl FnMut(&mut FrameAlloc, VirtAddr, u64),
+) -> Result<(), VmError> {
+    let mut addr = start.align_down(PAGE_SIZE);
+    let end = addr + align_up(size);
+    while addr < end {
+        match paging::mapping_size(addr) {
+            None => addr += PAGE_SIZE,
+            Some(size) if size != PAGE_SIZE && (!addr.is_aligned(size) || end - addr < size) => {
+                paging::split(addr, frames)?
+            }
+            Some(size) => {
+                f(frames, addr, size);
+                addr += size;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Changes the flags of a reserved range, splitting its region if needed
+///
+/// Pieces of a region which end up with the same flags again are merged back.
+pub fn protect(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    let start = start.align_down(PAGE_SIZE);
+    let end = start + align_up(size.max(1));
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let mut regions = REGIONS.lock();
+    let idx = regions
+        .iter()
+        .position(|r| r.map_or(false, |r| r.contains(start) && end <= r.end))
+        .ok_or(VmError::NotReserved)?;
+    let region = regions[idx].unwrap();
+
+    let below = Region {
+        end: start,
+        ..region
+    };
+    let above = Region {
+        start: end,
+        guard: 0,
+        ..region
+    };
+    let needed = (below.start != below.end) as usize + (above.start != above.end) as usize;
+    if regions.iter().filter(|r| r.is_none()).count() < needed {
+        return Err(VmError::Full);
+    }
+
+    {
+        let mut mapper = MAPPER.try_get().expect("paging not initialized").lock();
+        let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+
+        // Splitting the huge pages which stick out is all that can fail, and it keeps
+        // mapping the same frames, so the table is only changed once it's done
+        for_each_page(&mut frames, start, end - start, |_, _, _| ())?;
+        for_each_page(&mut frames, start, end - start, |_, addr, size| {
+            // Shared frames stay read-only until copied
+            let flags = match paging::flags(addr) {
+                Some(old) if old.contains(COPY_ON_WRITE) => {
+                    (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+                }
+                _ => flags,
+            };
+            let huge = flags | PageTableFlags::HUGE_PAGE;
+            let flush = unsafe {
+                match size {
+                    Size1GiB::SIZE => Mapper::<Size1GiB>::update_flags(
+                        &mut *mapper,
+                        Page::containing_address(addr),
+                        huge,
+                    )
+                    .map(|flush| flush.ignore()),
+                    Size2MiB::SIZE => Mapper::<Size2MiB>::update_flags(
+                        &mut *mapper,
+                        Page::containing_address(addr),
+                        huge,
+                    )
+                    .map(|flush| flush.ignore()),
+                    _ => Mapper::<Size4KiB>::update_flags(
+                        &mut *mapper,
+                        Page::containing_address(addr),
+                        flags,
+                    )
+                    .map(|flush| flush.ignore()),
+                }
+            };
+            if flush.is_ok() {
+                tlb::flush(addr);
+            }
+        })?;
+    }
+
+    regions[idx] = Some(Region {
+        start,
+        end,
+        guard: if start == region.start {
+            region.guard
+        } else {
+            0
+        },
+        flags,
+        ..region
+    });
+    for piece in [below, above].iter().filter(|r| r.start != r.end) {
+        insert(&mut regions[..], *piece)?;
+    }
+    merge(&mut regions[..], idx);
+    Ok(())
+}
+
+// Merges the region at `idx` with the adjacent pieces of the same reservation that have
+// the same flags, so splitting and restoring a range doesn't use up the table
+fn merge(regions: &mut [Option<Region>], idx: usize) {
+    let mut merged = regions[idx].take().expect("empty region slot");
+    let same = |a: &Region, b: &Region| {
+        a.base == b.base && a.kind == b.kind && a.flags == b.flags && a.demand == b.demand
+    };
+
+    while let Some(slot) = regions.iter_mut().find(|r| {
+        r.map_or(false, |r| {
+            same(&r, &merged) && (r.end == merged.start || r.start == merged.end)
+        })
+    }) {
+        let other = slot.take().unwrap();
+        merged = if other.end == merged.start {
+            Region {
+                end: merged.end,
+                ..other
+            }
+        } else {
+            Region {
+                end: other.end,
+                ..merged
+            }
+        };
+    }
+    regions[idx] = Some(merged);
+}
+
+/// Maps a copy of an anonymous range whose frames are shared until either side writes to them
+pub fn copy_on_write(start: VirtAddr, size: u64) -> Result<VirtAddr, VmError> {
+    let source = containing(start, size)?;
+    if source.kind != RegionKind::Anonymous {
+        return Err(VmError::WrongKind(source.kind));
+    }
+    let copy = reserve(size, 0, RegionKind::Anonymous, source.flags, true)?;
+
+    let result = {
+        let mut mapper = MAPPER.try_get().expect("paging not initialized").lock();
+        let mut frames = FRAMES.try_get().expect("paging not initialized").lock();
+        share_pages(&mut mapper, &mut frames, start, copy.start, size)
+    };
+    if let Err(err) = result {
+        unsafe { free(copy.start)? };
+        return Err(err);
+    }
+    Ok(copy.start)
+}
+
+fn share_pages(
+    mapper: &mut OffsetPageTable,
+    frames: &mut FrameAlloc,
+    source: VirtAddr,
+    copy: VirtAddr,
+    size: u64,
+) -> Result<(), VmError> {
+    for (src, dst) in pages(source, size).zip(pages(copy, size)) {
+        while paging::mapping_size(src.start_address()).map_or(false, |size| size > PAGE_SIZE) {
+            paging::split(src.start_address(), frames)?;
+        }
+        let (frame, flags) = match (
+            mapper.translate_page(src),
+            paging::flags(src.start_address()),
+        ) {
+            (Ok(frame), Some(flags)) => (frame, flags),
+            // Never touched, so the copy is zero filled on demand too
+            _ => continue,
+        };
+        let flags = if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+            (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+
+        unsafe {
+            mapper
+                .update_flags(src, flags)
+                .expect("page vanished")
+                .flush();
+            mapper.map_to(dst, frame, flags, frames)?.flush();
+        }
+        frames.share(frame);
+    }
+    Ok(())
+}
+
+/// Whether `addr` is in the guard pages below a region
+///
+/// Only tries to take the lock since it's used by the page fault handler.
+pub fn is_guard(addr: VirtAddr) -> bool {
+    REGIONS.try_lock().map_or(false, |regions| {
+        regions.iter().flatten().any(|r| r.in_guard(addr))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A frame was mapped or copied and the access can be retried
+    Mapped,
+    /// A lock the handler needs is held by another thread, retrying once it runs can work
+    Busy,
+    /// The faulting thread holds a lock the handler needs, so it can never be resolved
+    Deadlock,
+    OutOfMemory,
+    Invalid,
+}
+
+// The locks disable interrupts, so unless the holder blocked while holding one it's
+// the thread which faulted
+fn try_lock<T>(lock: &IrqMutex<T>) -> Result<IrqMutexGuard<T>, Fault> {
+    lock.try_lock().ok_or_else(|| {
+        if lock.held_by(thread::running()) {
+            Fault::Deadlock
+        } else {
+            Fault::Busy
+        }
+    })
+}
+
+/// Resolves a page fault by mapping a zeroed frame if the address is in a demand paged
+/// region, or by copying a copy-on-write frame that was written to
+///
+/// Runs in the page fault handler, so it only ever tries to take locks.
+pub fn handle_page_fault(addr: VirtAddr, err: PageFaultErrorCode) -> Fault {
+    let protection = err.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let write = err.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if protection && !write || !protection && err.contains(PageFaultErrorCode::USER_MODE) {
+        return Fault::Invalid;
+    }
+
+    let region = match try_lock(&REGIONS) {
+        Ok(regions) => regions.iter().flatten().find(|r| r.contains(addr)).copied(),
+        Err(fault) => return fault,
+    };
+    match region {
+        Some(region)
+            if write && !region.flags.contains(PageTableFlags::WRITABLE)
+                || err.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                    && region.flags.contains(PageTableFlags::NO_EXECUTE) =>
+        {
+            return Fault::Invalid
+        }
+        Some(region) if protection || region.demand => (),
+        // Copy-on-write user pages aren't in any region
+        None if protection => (),
+        _ => return Fault::Invalid,
+    }
+
+    let (mapper, frames) = match (MAPPER.try_get(), FRAMES.try_get()) {
+        (Some(mapper), Some(frames)) => (mapper, frames),
+        _ => return Fault::Invalid,
+    };
+    let (mut mapper, mut frames) = match (try_lock(mapper), try_lock(frames)) {
+        (Ok(mapper), Ok(frames)) => (mapper, frames),
+        (Err(fault), _) | (_, Err(fault)) => return fault,
+    };
+
+    if protection {
+        return match paging::copy_on_write(addr, &mut frames) {
+            Ok(true) => Fault::Mapped,
+            Ok(false) => Fault::Invalid,
+            Err(MapToError::FrameAllocationFailed) => Fault::OutOfMemory,
+            Err(_) => Fault::Invalid,
+        };
+    }
+    let region = region.unwrap();
+
+    let frame = match frames.allocate() {
+        Some(frame) => frame,
+        None => return Fault::OutOfMemory,
+    };
+    unsafe { zero(frame.start_address(), PAGE_SIZE) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, region.flags, &mut *frames) } {
+        Ok(flush) => {
+            flush.flush();
+            Fault::Mapped
+        }
+        Err(err) => {
+            unsafe { frames.deallocate(frame) };
+            match err {
+                MapToError::FrameAllocationFailed => Fault::OutOfMemory,
+                // Mapped by someone else since the fault
+                MapToError::PageAlreadyMapped(_) => Fault::Mapped,
+                MapToError::ParentEntryHugePage => Fault::Invalid,
+            }
+        }
+    }
+}
+
+/// Reserves a demand paged range of kernel memory
+pub fn allocate_anonymous(size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmError> {
+    reserve(size, 0, RegionKind::Anonymous, flags, true).map(|r| r.start)
+}
+
+/// Unmaps an anonymous range and frees the frames which were faulted in
+///
+/// # Safety
+/// Nothing can use the range anymore
+pub unsafe fn free_anonymous(start: VirtAddr) {
+    match find(start) {
+        Some(region) if region.kind == RegionKind::Anonymous => {
+            free(region.start).expect("region vanished")
+        }
+        Some(region) => panic!("{:?} isn't anonymous", region),
+        None => (),
+    }
+}
+
+/// Table of the reserved regions, sorted by address
+pub struct Layout;
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut last = None;
+        // Selection by address, the table isn't sorted and there's no allocating here.
+        // The lock isn't held while writing, which could fault in stack pages.
+        while let Some(r) = next_region(last) {
+            last = Some(r.start);
+            let flag = |set: bool, c: char| if set { c } else { '-' };
+            writeln!(
+                f,
+                "{:#014x}-{:#014x} {:>8} KiB r{}{} {:?}{}{}",
+                r.start.as_u64(),
+                r.end.as_u64(),
+                r.size() / 1024,
+                flag(r.flags.contains(PageTableFlags::WRITABLE), 'w'),
+                flag(!r.flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+                r.kind,
+                if r.demand { " demand" } else { "" },
+                if r.guard != 0 { " guarded" } else { "" },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The region with the lowest start above `after`
+fn next_region(after: Option<VirtAddr>) -> Option<Region> {
+    REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .filter(|r| after.map_or(true, |after| r.start > after))
+        .min_by_key(|r| r.start)
+        .copied()
+}
+
+/// Prints the kernel address space layout to the screen and serial port
+pub fn dump() {
+    println!("{}", Layout);
+    s1println!("{}", Layout);
+}
+
+fn pages(start: VirtAddr, size: u64) -> PageRange<Size4KiB> {
+    let start = start.align_down(PAGE_SIZE);
+    Page::range(
+        Page::containing_address(start),
+        Page::containing_address(start + align_up(size)),
+    )
+}
+
+unsafe fn zero(phys: PhysAddr, len: u64) {
+    let phys_offset = *PHYS_OFFSET.try_get().expect("paging not initialized");
+    let virt = phys_offset + phys.as_u64();
+    core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, len as usize);
+}
+
+fn align_up(size: u64) -> u64 {
+    align_to(size, PAGE_SIZE)
+}
+fn align_to(val: u64, align: u64) -> u64 {
+    (val + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::{frame::FRAMES, paging};
+    use x86_64::structures::paging::{
+        FrameAllocator, FrameDeallocator, PageTableFlags, PhysFrame, Size2MiB,
+    };
+
+    #[test_case]
+    fn anonymous() {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = super::allocate_anonymous(3 * 4096, flags).unwrap();
+        let ptr = start.as_mut_ptr::<u64>();
+
+        unsafe {
+            // Fresh pages read as zero, then keep what was written
+            assert_eq!(ptr.add(1024).read_volatile(), 0);
+            ptr.add(1024).write_volatile(42);
+            ptr.add(1024 * 2).write_volatile(7);
+            assert_eq!(ptr.add(1024).read_volatile(), 42);
+            super::free_anonymous(start);
+        }
+        assert_eq!(super::find(start), None);
+    }
+
+    #[test_case]
+    fn protect() {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = super::allocate_anonymous(3 * 4096, flags).unwrap();
+        super::map(start, 3 * 4096).unwrap();
+
+        super::protect(start + 4096u64, 4096, PageTableFlags::NO_EXECUTE).unwrap();
+        let below = super::find(start).unwrap();
+        let middle = super::find(start + 4096u64).unwrap();
+        let above = super::find(start + 2 * 4096u64).unwrap();
+        assert_eq!(below.size(), 4096);
+        assert!(!middle.flags.contains(PageTableFlags::WRITABLE));
+        assert!(above.flags.contains(PageTableFlags::WRITABLE));
+        let writable = |addr| {
+            paging::flags(addr)
+                .unwrap()
+                .contains(PageTableFlags::WRITABLE)
+        };
+        assert!(writable(start));
+        assert!(!writable(start + 4096u64));
+        assert!(writable(start + 2 * 4096u64));
+
+        // Restoring the flags merges the pieces again, however often it's done
+        for _ in 0..super::MAX_REGIONS {
+            super::protect(start + 4096u64, 4096, PageTableFlags::NO_EXECUTE).unwrap();
+            super::protect(start + 4096u64, 4096, flags).unwrap();
+        }
+        assert_eq!(super::find(start).unwrap().size(), 3 * 4096);
+        assert!(writable(start + 4096u64));
+
+        unsafe { super::free(start).unwrap() };
+    }
+
+    #[test_case]
+    fn copy_on_write() {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = super::allocate_anonymous(2 * 4096, flags).unwrap();
+        let ptr = start.as_mut_ptr::<u64>();
+        unsafe {
+            ptr.write_volatile(1);
+            ptr.add(512).write_volatile(2);
+        }
+
+        let copy = super::copy_on_write(start, 2 * 4096).unwrap();
+        let copy_ptr = copy.as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(copy_ptr.read_volatile(), 1);
+            copy_ptr.write_volatile(5);
+            ptr.add(512).write_volatile(7);
+
+            assert_eq!(ptr.read_volatile(), 1);
+            assert_eq!(copy_ptr.read_volatile(), 5);
+            assert_eq!(copy_ptr.add(512).read_volatile(), 2);
+
+            super::free_anonymous(copy);
+            super::free_anonymous(start);
+        }
+    }
+
+    #[test_case]
+    fn huge_pages() {
+        // Mapping falls back to 4 KiB pages without free 2 MiB frames, so only run with them
+        {
+            let mut frames = FRAMES.try_get().unwrap().lock();
+            let huge: [Option<PhysFrame<Size2MiB>>; 2] =
+                [frames.allocate_frame(), frames.allocate_frame()];
+            for frame in huge.iter().flatten() {
+                unsafe { frames.deallocate_frame(*frame) };
+            }
+            if huge.iter().any(Option::is_none) {
+                return;
+            }
+        }
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = super::allocate_anonymous(2 * 2 * 1024 * 1024, flags).unwrap();
+        super::map(start, 2 * 2 * 1024 * 1024).unwrap();
+        assert_eq!(paging::mapping_size(start), Some(2 * 1024 * 1024));
+
+        unsafe { start.as_mut_ptr::<u64>().write_volatile(3) };
+        // Only the second page changes, which splits the first huge page
+        super::protect(start + 4096u64, 4096, PageTableFlags::NO_EXECUTE).unwrap();
+        assert_eq!(paging::mapping_size(start), Some(4096));
+        assert_eq!(
+            paging::mapping_size(start + 2 * 1024 * 1024u64),
+            Some(2 * 1024 * 1024)
+        );
+        assert_eq!(unsafe { start.as_ptr::<u64>().read_volatile() }, 3);
+
+        // Frees the pieces split off by protect too
+        unsafe { super::free(start).unwrap() };
+        assert_eq!(super::find(start + 2 * 4096u64), None);
+    }
+}
@@ -1,18 +1,25 @@
-use core::ptr;
+use core::{cell::UnsafeCell, ptr};
 
+/// Value which is only ever read and written whole, without the accesses being optimized out
+///
+/// Writes take `&self` since device registers are usually shared, like the local APIC.
 #[derive(Debug)]
 #[repr(transparent)]
-pub struct Volatile<T: Copy>(T);
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+// Every access is a single volatile load or store, racing ones are up to whoever shares
+// the value, as they would be with the device on the other side of a register
+unsafe impl<T: Copy + Send> Sync for Volatile<T> {}
 
 impl<T: Copy> Volatile<T> {
     pub const fn new(val: T) -> Self {
-        Self(val)
+        Self(UnsafeCell::new(val))
     }
 
     pub fn read(&self) -> T {
-        unsafe { ptr::read_volatile(&self.0) }
+        unsafe { ptr::read_volatile(self.0.get()) }
     }
-    pub fn write(&mut self, val: T) {
-        unsafe { ptr::write_volatile(&mut self.0, val) }
+    pub fn write(&self, val: T) {
+        unsafe { ptr::write_volatile(self.0.get(), val) }
     }
 }
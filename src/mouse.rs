@@ -0,0 +1,247 @@
+use crate::{
+    ps2::{self, Channel, Device},
+    sync::{ArrayQueue, AtomicWaker, Mutex, Once},
+    task::Stream,
+};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use x86_64::instructions::interrupts;
+
+const QUEUE_SIZE: usize = 256;
+
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const IDENTIFY: u8 = 0xF2;
+const ENABLE_REPORTING: u8 = 0xF4;
+const SET_DEFAULTS: u8 = 0xF6;
+
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+const ALWAYS_SET: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+static BYTES: Once<ArrayQueue<u8>> = Once::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static STATE: Mutex<State> = Mutex::new(State {
+    device: Device::Mouse,
+    packet: [0; 4],
+    len: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub fourth: bool,
+    pub fifth: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right
+    pub dx: i16,
+    /// Movement up
+    pub dy: i16,
+    /// Scroll towards the user
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+struct State {
+    device: Device,
+    packet: [u8; 4],
+    len: usize,
+}
+
+impl State {
+    fn packet_len(&self) -> usize {
+        match self.device {
+            Device::WheelMouse | Device::FiveButtonMouse => 4,
+            _ => 3,
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Resynchronise on the bit that is always set in the first byte
+        if self.len == 0 && byte & ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_len() {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y, extra] = self.packet;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None;
+        }
+        let axis = |val: u8, negative: bool| val as i16 - if negative { 0x100 } else { 0 };
+
+        let (wheel, fourth, fifth) = match self.device {
+            Device::WheelMouse => (extra as i8, false, false),
+            // Only the low nibble is the wheel, sign extended
+            Device::FiveButtonMouse => (
+                (extra << 4) as i8 >> 4,
+                extra & 0x10 != 0,
+                extra & 0x20 != 0,
+            ),
+            _ => (0, false, false),
+        };
+
+        Some(MouseEvent {
+            dx: axis(x, flags & X_SIGN != 0),
+            dy: axis(y, flags & Y_SIGN != 0),
+            wheel,
+            buttons: Buttons {
+                left: flags & LEFT != 0,
+                right: flags & RIGHT != 0,
+                middle: flags & MIDDLE != 0,
+                fourth,
+                fifth,
+            },
+        })
+    }
+}
+
+/// Turns on reporting for the mouse on the second PS/2 port, enabling the scroll
+/// wheel and extra buttons when it has them
+pub fn init() {
+    BYTES.init_once(|| ArrayQueue::new(QUEUE_SIZE));
+
+    let device = match ps2::device(Channel::Second) {
+        Some(device) if device.is_mouse() => device,
+        _ => return,
+    };
+
+    let device = interrupts::without_interrupts(|| unsafe {
+        let config = match ps2::read_config() {
+            Some(config) => config,
+            None => return device,
+        };
+        ps2::write_config(config & !ps2::CONFIG_IRQ_SECOND);
+
+        ps2::send(Channel::Second, SET_DEFAULTS);
+        // Magic sample rate sequences unlock the wheel, then the extra buttons
+        let mut device = device;
+        for &(rates, unlocks) in &[
+            ([200, 100, 80], Device::WheelMouse),
+            ([200, 200, 80], Device::FiveButtonMouse),
+        ] {
+            for &rate in &rates {
+                ps2::send(Channel::Second, SET_SAMPLE_RATE);
+                ps2::send(Channel::Second, rate);
+            }
+            if identify() == Some(unlocks) {
+                device = unlocks;
+            }
+        }
+        ps2::send(Channel::Second, ENABLE_REPORTING);
+
+        ps2::write_config(config);
+        device
+    });
+
+    STATE.lock().device = device;
+}
+
+unsafe fn identify() -> Option<Device> {
+    if !ps2::send(Channel::Second, IDENTIFY) {
+        return None;
+    }
+    let id = ps2::read_data(10)?;
+    Some(match id {
+        0x00 => Device::Mouse,
+        0x03 => Device::WheelMouse,
+        0x04 => Device::FiveButtonMouse,
+        id => Device::Unknown(id),
+    })
+}
+
+/// Queues a byte from the interrupt handler, dropping it if nobody keeps up
+pub(crate) fn push_byte(byte: u8) {
+    if let Some(queue) = BYTES.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Decodes queued bytes until one completes a packet
+pub fn try_read_event() -> Option<MouseEvent> {
+    let queue = BYTES.try_get()?;
+    let mut state = STATE.lock();
+
+    while let Some(byte) = queue.pop() {
+        if let Some(event) = state.decode(byte) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+/// Halts until a mouse event comes in
+pub fn read_event() -> MouseEvent {
+    loop {
+        if let Some(event) = try_read_event() {
+            return event;
+        }
+
+        interrupts::disable();
+        if BYTES.try_get().map_or(true, |queue| queue.is_empty()) {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Mouse events as they come in, with the same caveats as `keyboard::keys`
+pub fn events() -> Events {
+    Events(())
+}
+
+pub struct Events(());
+
+impl Stream for Events {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        if let Some(event) = try_read_event() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(cx.waker());
+        match try_read_event() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::STATE;
+
+    #[test_case]
+    fn packet() {
+        // Left button, 5 right and 2 down
+        let packet = [0x29, 0x05, 0xFE, 0x00];
+        let len = STATE.lock().packet_len();
+        for &byte in &packet[..len] {
+            super::push_byte(byte);
+        }
+
+        let event = super::read_event();
+        assert!(event.buttons.left && !event.buttons.right);
+        assert_eq!((event.dx, event.dy, event.wheel), (5, -2, 0));
+    }
+}
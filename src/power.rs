@@ -0,0 +1,107 @@
+use crate::{
+    acpi::{Fadt, GenericAddress, ACPI},
+    mem::{
+        mmio::{CacheMode, Mmio},
+        Volatile,
+    },
+};
+use x86_64::{
+    instructions::{interrupts, port::Port, tables},
+    structures::DescriptorTablePointer,
+    PhysAddr,
+};
+
+const SLP_TYP: u16 = 0b111 << 10;
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1;
+
+/// Turns the machine off through ACPI, falling back to emulator specific ports
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some(acpi) = ACPI.try_get() {
+        if let (Some(fadt), Some((slp_typa, slp_typb))) = (acpi.fadt, acpi.s5_sleep_type()) {
+            unsafe {
+                enable_acpi_mode(&fadt);
+
+                if fadt.pm1b_control_block != 0 {
+                    sleep(fadt.pm1b_control_block as u16, slp_typb);
+                }
+                sleep(fadt.pm1a_control_block as u16, slp_typa);
+            }
+        }
+    }
+
+    // QEMU, older QEMU and Bochs, VirtualBox
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+
+    crate::halt()
+}
+
+/// Resets the machine through the ACPI reset register, the keyboard controller or a triple fault
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(fadt) = ACPI.try_get().and_then(|acpi| acpi.fadt) {
+        if let Some(reset) = fadt.reset_register {
+            unsafe { write_reset_register(&reset, fadt.reset_value) };
+        }
+    }
+
+    unsafe {
+        let mut status: Port<u8> = Port::new(0x64);
+        for _ in 0..0x10000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(0xFE);
+    }
+
+    // With no IDT any exception escalates to a triple fault
+    unsafe {
+        tables::lidt(&DescriptorTablePointer { limit: 0, base: 0 });
+    }
+    interrupts::int3();
+
+    crate::halt()
+}
+
+unsafe fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if pm1a.read() & SCI_EN != 0 || fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+
+    Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+    for _ in 0..0x10000 {
+        if pm1a.read() & SCI_EN != 0 {
+            break;
+        }
+    }
+}
+
+// Enters a sleep state through a PM1 control register, keeping its other bits like SCI_EN
+unsafe fn sleep(control: u16, sleep_type: u8) {
+    let mut port: Port<u16> = Port::new(control);
+    let value = port.read() & !(SLP_TYP | SLP_EN);
+    port.write(value | ((sleep_type as u16) << 10 & SLP_TYP) | SLP_EN);
+}
+
+unsafe fn write_reset_register(reset: &GenericAddress, value: u8) {
+    match reset.address_space {
+        GenericAddress::SYSTEM_IO => Port::<u8>::new(reset.address as u16).write(value),
+        GenericAddress::SYSTEM_MEMORY => {
+            if let Ok(reg) =
+                Mmio::<Volatile<u8>>::map(PhysAddr::new(reset.address), CacheMode::Uncached)
+            {
+                reg.write(value);
+            }
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,295 @@
+use crate::sync::Once;
+use x86_64::instructions::{interrupts, port::Port};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+const SECOND_OUTPUT: u8 = 1 << 5;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xA7;
+const ENABLE_SECOND: u8 = 0xA8;
+const TEST_SECOND: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_FIRST: u8 = 0xAB;
+const DISABLE_FIRST: u8 = 0xAD;
+const ENABLE_FIRST: u8 = 0xAE;
+const WRITE_SECOND: u8 = 0xD4;
+
+pub const CONFIG_IRQ_FIRST: u8 = 1 << 0;
+pub const CONFIG_IRQ_SECOND: u8 = 1 << 1;
+pub const CONFIG_CLOCK_FIRST_OFF: u8 = 1 << 4;
+pub const CONFIG_CLOCK_SECOND_OFF: u8 = 1 << 5;
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const RESET: u8 = 0xFF;
+const IDENTIFY: u8 = 0xF2;
+const ENABLE_SCANNING: u8 = 0xF4;
+const DISABLE_SCANNING: u8 = 0xF5;
+
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+
+const TIMEOUT_MS: u64 = 10;
+const RESET_TIMEOUT_MS: u64 = 500;
+
+pub static DEVICES: Once<[Option<Device>; 2]> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Keyboard,
+    Mouse,
+    WheelMouse,
+    FiveButtonMouse,
+    Unknown(u8),
+}
+
+impl Device {
+    pub fn is_mouse(&self) -> bool {
+        matches!(self, Self::Mouse | Self::WheelMouse | Self::FiveButtonMouse)
+    }
+
+    fn from_id(id: &[u8]) -> Self {
+        match id {
+            // AT keyboards don't answer at all, MF2 ones with 0xAB and a second byte
+            [] | [0xAB, ..] | [0xAC, ..] => Self::Keyboard,
+            [0x00] => Self::Mouse,
+            [0x03] => Self::WheelMouse,
+            [0x04] => Self::FiveButtonMouse,
+            [id, ..] => Self::Unknown(*id),
+        }
+    }
+}
+
+/// Tests the controller, then resets and identifies the devices on both ports,
+/// leaving their interrupts enabled and translation to scancode set 1 on
+pub fn init() {
+    let devices = interrupts::without_interrupts(|| unsafe { init_controller() });
+    DEVICES.init_once(|| devices.unwrap_or([None, None]));
+}
+
+pub fn device(channel: Channel) -> Option<Device> {
+    DEVICES.try_get()?[channel as usize]
+}
+
+unsafe fn init_controller() -> Option<[Option<Device>; 2]> {
+    write_command(DISABLE_FIRST);
+    write_command(DISABLE_SECOND);
+    flush();
+
+    let original = match read_config() {
+        Some(config) => config,
+        None => {
+            // Nothing was changed yet, the keyboard is almost always on the first port
+            write_command(ENABLE_FIRST);
+            return None;
+        }
+    };
+    let devices = setup_controller(original);
+    if devices.is_none() {
+        restore(original);
+    }
+    devices
+}
+
+// Puts back the configuration the firmware left, so a keyboard it set up keeps working
+unsafe fn restore(config: u8) {
+    write_config(config);
+    if config & CONFIG_CLOCK_FIRST_OFF == 0 {
+        write_command(ENABLE_FIRST);
+    }
+    if config & CONFIG_CLOCK_SECOND_OFF == 0 {
+        write_command(ENABLE_SECOND);
+    }
+}
+
+unsafe fn setup_controller(original: u8) -> Option<[Option<Device>; 2]> {
+    let mut config = original & !(CONFIG_IRQ_FIRST | CONFIG_IRQ_SECOND | CONFIG_TRANSLATION);
+    write_config(config);
+
+    write_command(SELF_TEST);
+    if read_data(TIMEOUT_MS)? != 0x55 {
+        return None;
+    }
+    // The self test can reset the controller on some hardware
+    write_config(config);
+
+    // The second clock only turns on if there is a second port
+    write_command(ENABLE_SECOND);
+    let dual = read_config()? & CONFIG_CLOCK_SECOND_OFF == 0;
+    write_command(DISABLE_SECOND);
+
+    let mut devices = [None, None];
+    let channels = [
+        (
+            Channel::First,
+            true,
+            TEST_FIRST,
+            ENABLE_FIRST,
+            CONFIG_IRQ_FIRST,
+        ),
+        (
+            Channel::Second,
+            dual,
+            TEST_SECOND,
+            ENABLE_SECOND,
+            CONFIG_IRQ_SECOND,
+        ),
+    ];
+    for &(channel, exists, test, enable, irq) in &channels {
+        if !exists {
+            continue;
+        }
+        write_command(test);
+        if read_data(TIMEOUT_MS) != Some(0x00) {
+            // Left as the firmware set it up, in case it works anyway
+            config |= original & irq;
+            if channel == Channel::First {
+                config |= original & CONFIG_TRANSLATION;
+            }
+            continue;
+        }
+
+        write_command(enable);
+        devices[channel as usize] = identify(channel);
+        if devices[channel as usize].is_some() {
+            config |= irq;
+        }
+    }
+
+    if devices[0] == Some(Device::Keyboard) {
+        send(Channel::First, ENABLE_SCANNING);
+        config |= CONFIG_TRANSLATION;
+    }
+    config &= !(CONFIG_CLOCK_FIRST_OFF | CONFIG_CLOCK_SECOND_OFF);
+    write_config(config);
+
+    Some(devices)
+}
+
+unsafe fn identify(channel: Channel) -> Option<Device> {
+    if !send(channel, RESET) || read_data(RESET_TIMEOUT_MS)? != 0xAA {
+        return None;
+    }
+    // Mice send their ID after passing the self test
+    flush();
+
+    if !send(channel, DISABLE_SCANNING) || !send(channel, IDENTIFY) {
+        return None;
+    }
+    let mut id = [0; 2];
+    let mut len = 0;
+    while len < id.len() {
+        match read_data(TIMEOUT_MS) {
+            Some(byte) => id[len] = byte,
+            None => break,
+        }
+        len += 1;
+    }
+
+    Some(Device::from_id(&id[..len]))
+}
+
+pub fn read_config() -> Option<u8> {
+    unsafe {
+        write_command(READ_CONFIG);
+        read_data(TIMEOUT_MS)
+    }
+}
+
+/// # Safety
+/// Changing the configuration can disable the devices
+pub unsafe fn write_config(config: u8) {
+    write_command(WRITE_CONFIG);
+    write_data(config);
+}
+
+/// Sends a byte to a device, retrying when asked to, and returns whether it was acknowledged
+///
+/// # Safety
+/// The port's interrupt has to be off, otherwise its handler takes the reply
+pub unsafe fn send(channel: Channel, byte: u8) -> bool {
+    for _ in 0..3 {
+        write(channel, byte);
+        match read_data(TIMEOUT_MS) {
+            Some(ACK) => return true,
+            Some(RESEND) => continue,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Sends a byte to a device without waiting for a reply
+///
+/// # Safety
+/// The device has to expect the byte
+pub unsafe fn write(channel: Channel, byte: u8) {
+    if channel == Channel::Second {
+        write_command(WRITE_SECOND);
+    }
+    write_data(byte);
+}
+
+/// # Safety
+/// The command can change the controller's state
+pub unsafe fn write_command(command: u8) {
+    wait_writable();
+    Port::new(STATUS_PORT).write(command);
+}
+
+unsafe fn write_data(data: u8) {
+    wait_writable();
+    Port::new(DATA_PORT).write(data);
+}
+
+/// Polls for a byte from the controller or a device
+// Port reads take around a microsecond, which is precise enough for timeouts
+pub fn read_data(timeout_ms: u64) -> Option<u8> {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    for _ in 0..timeout_ms * 1000 {
+        if unsafe { status.read() } & OUTPUT_FULL != 0 {
+            return Some(unsafe { Port::new(DATA_PORT).read() });
+        }
+    }
+    None
+}
+
+/// Polls until the keyboard sends something, for when its interrupt can't be relied on
+///
+/// Anything received before is discarded, and so is mouse data.
+pub fn wait_for_keyboard() {
+    flush();
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    loop {
+        let flags = unsafe { status.read() };
+        if flags & OUTPUT_FULL != 0 {
+            let _: u8 = unsafe { Port::new(DATA_PORT).read() };
+            if flags & SECOND_OUTPUT == 0 {
+                return;
+            }
+        }
+    }
+}
+
+fn flush() {
+    while read_data(1).is_some() {}
+}
+
+fn wait_writable() {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    for _ in 0..TIMEOUT_MS * 1000 {
+        if unsafe { status.read() } & INPUT_FULL == 0 {
+            break;
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::time::tsc::read as tsc;
 use core::{mem, ptr};
 use rand_core::RngCore;
 use x86_64::instructions::random::RdRand;
@@ -277,15 +278,6 @@ impl JitterRng {
     }
 }
 
-fn tsc() -> u64 {
-    let low: u32;
-    let high: u32;
-    unsafe {
-        asm!("rdtsc", lateout("eax") low, lateout("edx") high);
-    }
-    low as u64 | (high as u64) << 32
-}
-
 #[cfg(test)]
 mod tests {
     #[test_case]
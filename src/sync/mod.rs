@@ -1,5 +1,9 @@
 pub mod mutex;
 pub mod once;
+pub mod queue;
+pub mod waker;
 
-pub use mutex::Mutex;
+pub use mutex::{IrqMutex, Mutex};
 pub use once::{Lazy, Once};
+pub use queue::ArrayQueue;
+pub use waker::AtomicWaker;
@@ -1,8 +1,11 @@
+use crate::thread::{self, ThreadId};
 use core::{
     cell::UnsafeCell,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{self, AtomicBool, Ordering},
+    sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
+use x86_64::instructions::interrupts;
 
 #[derive(Debug)]
 pub struct Mutex<T: ?Sized> {
@@ -32,6 +35,17 @@ impl<'a, T: ?Sized> Mutex<T> {
             val: unsafe { &mut *self.val.get() },
         }
     }
+
+    pub fn try_lock(&'a self) -> Option<MutexGuard<'a, T>> {
+        if self.lock.compare_and_swap(false, true, Ordering::Acquire) {
+            return None;
+        }
+
+        Some(MutexGuard {
+            lock: &self.lock,
+            val: unsafe { &mut *self.val.get() },
+        })
+    }
 }
 
 pub struct MutexGuard<'a, T: ?Sized> {
@@ -57,3 +71,105 @@ impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
         self.val
     }
 }
+
+const NO_OWNER: u64 = u64::MAX;
+
+// How many `IrqMutex`es are held, and whether interrupts were enabled before the first one
+// was locked. A count instead of a flag per guard so they can be released in any order.
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Mutex which keeps interrupts disabled while it's held, so its holder can't be preempted
+///
+/// The holding thread is recorded, an exception handler which finds the lock taken
+/// can tell whether the thread it interrupted holds it. Locking the first one faults
+/// in the top of the stack, so it's fine for the page fault handler to need the lock.
+#[derive(Debug)]
+pub struct IrqMutex<T: ?Sized> {
+    owner: AtomicU64,
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            owner: AtomicU64::new(NO_OWNER),
+            inner: Mutex::new(val),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> IrqMutex<T> {
+    pub fn lock(&'a self) -> IrqMutexGuard<'a, T> {
+        // Faulting on the stack while one is held would be too late
+        if IRQ_DEPTH.load(Ordering::SeqCst) == 0 {
+            thread::probe_stack();
+        }
+        disable_interrupts();
+        self.guard(self.inner.lock())
+    }
+
+    pub fn try_lock(&'a self) -> Option<IrqMutexGuard<'a, T>> {
+        disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.guard(guard)),
+            None => {
+                restore_interrupts();
+                None
+            }
+        }
+    }
+
+    /// Whether `thread` holds the lock right now
+    pub fn held_by(&self, thread: ThreadId) -> bool {
+        self.owner.load(Ordering::SeqCst) == thread.as_u64()
+    }
+
+    fn guard(&'a self, guard: MutexGuard<'a, T>) -> IrqMutexGuard<'a, T> {
+        self.owner
+            .store(thread::running().as_u64(), Ordering::SeqCst);
+        IrqMutexGuard {
+            owner: &self.owner,
+            guard: ManuallyDrop::new(guard),
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    owner: &'a AtomicU64,
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::SeqCst);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore_interrupts();
+    }
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+fn disable_interrupts() {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if IRQ_DEPTH.fetch_add(1, Ordering::SeqCst) == 0 {
+        IRQ_ENABLED.store(enabled, Ordering::SeqCst);
+    }
+}
+fn restore_interrupts() {
+    if IRQ_DEPTH.fetch_sub(1, Ordering::SeqCst) == 1 && IRQ_ENABLED.load(Ordering::SeqCst) {
+        interrupts::enable();
+    }
+}
@@ -0,0 +1,283 @@
+use super::{Task, TaskId};
+use crate::sync::{ArrayQueue, Mutex};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    mem::ManuallyDrop,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use x86_64::instructions::interrupts;
+
+const QUEUE_SIZE: usize = 128;
+
+/// Runs tasks on the current CPU, halting until an interrupt wakes one when none are ready
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+    ready: Arc<ReadyQueue>,
+    spawned: Arc<Mutex<Vec<Task>>>,
+}
+
+// Ids of woken tasks, each queued at most once until it's polled. Wakeups which
+// don't fit are only flagged and the executor looks for the woken tasks itself.
+struct ReadyQueue {
+    ids: ArrayQueue<TaskId>,
+    overflowed: AtomicBool,
+}
+
+impl ReadyQueue {
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && !self.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            ready: Arc::new(ReadyQueue {
+                ids: ArrayQueue::new(QUEUE_SIZE),
+                overflowed: AtomicBool::new(false),
+            }),
+            spawned: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        self.add(Task::new(future))
+    }
+
+    /// Handle which can spawn tasks from inside other tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
+        }
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Runs until every task has completed
+    pub fn run_until_complete(&mut self) {
+        loop {
+            self.run_ready();
+            if self.tasks.is_empty() && self.spawned.lock().is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    fn add(&mut self, task: Task) -> TaskId {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task {:?} spawned twice", id);
+        }
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake();
+        self.wakers.insert(id, waker);
+        id
+    }
+
+    fn run_ready(&mut self) {
+        loop {
+            let spawned = core::mem::take(&mut *self.spawned.lock());
+            for task in spawned {
+                self.add(task);
+            }
+
+            if let Some(id) = self.ready.ids.pop() {
+                self.poll(id);
+            } else if self.ready.overflowed.swap(false, Ordering::SeqCst) {
+                let woken: Vec<_> = self
+                    .wakers
+                    .values()
+                    .filter(|w| w.queued.load(Ordering::SeqCst))
+                    .map(|w| w.id)
+                    .collect();
+                for id in woken {
+                    self.poll(id);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn poll(&mut self, id: TaskId) {
+        // Wakers can outlive their task
+        let (task, waker) = match (self.tasks.get_mut(&id), self.wakers.get(&id)) {
+            (Some(task), Some(waker)) => (task, waker),
+            _ => return,
+        };
+        // Cleared before polling so a wakeup during the poll queues the task again.
+        // Already clear if the task was polled since it was queued.
+        if !waker.queued.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let waker = TaskWaker::waker(waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        if let Poll::Ready(()) = task.poll(&mut cx) {
+            self.tasks.remove(&id);
+            self.wakers.remove(&id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Checking and halting with interrupts off means a wakeup can't slip in between
+        interrupts::disable();
+        if self.ready.is_empty() && self.spawned.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Arc<Mutex<Vec<Task>>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id;
+        self.spawned.lock().push(task);
+        id
+    }
+}
+
+// Waking only pushes to the ready queue, so it neither allocates nor locks
+struct TaskWaker {
+    id: TaskId,
+    // Set while the task is in the ready queue or flagged as woken
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl TaskWaker {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::clone_raw,
+        Self::wake_raw,
+        Self::wake_by_ref_raw,
+        Self::drop_raw,
+    );
+
+    fn waker(waker: Arc<Self>) -> Waker {
+        let raw = Arc::into_raw(waker);
+        unsafe { Waker::from_raw(RawWaker::new(raw as *const (), &Self::VTABLE)) }
+    }
+
+    fn wake(&self) {
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if self.ready.ids.push(self.id).is_err() {
+            self.ready.overflowed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    unsafe fn clone_raw(data: *const ()) -> RawWaker {
+        let waker = ManuallyDrop::new(Arc::from_raw(data as *const Self));
+        let clone = Arc::clone(&waker);
+        RawWaker::new(Arc::into_raw(clone) as *const (), &Self::VTABLE)
+    }
+
+    unsafe fn wake_raw(data: *const ()) {
+        Self::wake_by_ref_raw(data);
+        Self::drop_raw(data);
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        (*(data as *const Self)).wake();
+    }
+
+    unsafe fn drop_raw(data: *const ()) {
+        drop(Arc::from_raw(data as *const Self));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Executor;
+    use crate::{sync::Mutex, task::yield_now};
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test_case]
+    fn interleave() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut executor = Executor::new();
+
+        for name in 0..2 {
+            let log = log.clone();
+            executor.spawn(async move {
+                for step in 0..3 {
+                    log.lock().push((name, step));
+                    yield_now().await;
+                }
+            });
+        }
+        executor.run_until_complete();
+
+        assert_eq!(
+            *log.lock(),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+        );
+    }
+
+    #[test_case]
+    fn spawner() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+
+        let outer = log.clone();
+        executor.spawn(async move {
+            outer.lock().push(0);
+            let inner = outer.clone();
+            spawner.spawn(async move { inner.lock().push(1) });
+        });
+        executor.run_until_complete();
+
+        assert_eq!(*log.lock(), vec![0, 1]);
+    }
+
+    #[test_case]
+    fn overflow() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut executor = Executor::new();
+
+        // Spawned and woken again faster than the ready queue drains
+        let tasks = super::QUEUE_SIZE * 3;
+        for _ in 0..tasks {
+            let done = done.clone();
+            executor.spawn(async move {
+                yield_now().await;
+                yield_now().await;
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        executor.run_until_complete();
+
+        assert_eq!(done.load(Ordering::SeqCst), tasks);
+    }
+}
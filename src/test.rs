@@ -1,3 +1,5 @@
+use core::{fmt, panic::PanicInfo};
+
 pub trait Test {
     fn run(&self);
 }
@@ -21,9 +23,44 @@ pub fn runner(tests: &[&dyn Test]) {
     crate::qemu::exit(crate::qemu::ExitCode::Success)
 }
 
-pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+pub fn panic_handler(info: &PanicInfo) -> ! {
     s1println!("err");
     s1println!("{}", info);
     crate::qemu::exit(crate::qemu::ExitCode::Failed);
     crate::halt();
 }
+
+/// Panic handler for tests which pass by panicking with `expected` in the message
+pub fn should_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    let mut message = Prefix {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = fmt::write(&mut message, format_args!("{}", info));
+
+    let message = &message.buf[..message.len];
+    if message
+        .windows(expected.len())
+        .any(|w| w == expected.as_bytes())
+    {
+        s1println!("ok");
+        crate::qemu::exit(crate::qemu::ExitCode::Success);
+        crate::halt();
+    }
+    panic_handler(info)
+}
+
+// The start of a formatted message, without allocating
+struct Prefix {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for Prefix {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
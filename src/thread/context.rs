@@ -0,0 +1,74 @@
+use super::scheduler;
+use alloc::boxed::Box;
+
+pub type Entry = Box<dyn FnOnce() + Send>;
+
+// Callee-saved registers are pushed on the old stack and popped from the new one,
+// everything else is saved by whoever called the switch
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_trampoline
+thread_trampoline:
+    mov rdi, r12
+    sti
+    call thread_start
+    ud2
+.att_syntax
+"#
+);
+
+extern "C" {
+    /// Saves the current stack pointer to `old` and resumes the context saved at `new`
+    pub fn switch_context(old: *mut u64, new: u64);
+    fn thread_trampoline();
+}
+
+/// Lays out a stack so that switching to it calls `entry`, returning the stack pointer
+///
+/// # Safety
+/// `top` has to be the end of a writable stack of at least 64 bytes
+pub unsafe fn init_stack(top: u64, entry: Entry) -> u64 {
+    // The trampoline is entered by `ret` and has to see a 16 byte aligned stack before its call
+    let top = top & !0xF;
+    let frame = (top - 7 * 8) as *mut u64;
+
+    let entry = Box::into_raw(Box::new(entry));
+    let registers = [
+        0,            // r15
+        0,            // r14
+        0,            // r13
+        entry as u64, // r12
+        0,            // rbx
+        0,            // rbp
+        thread_trampoline as u64,
+    ];
+    frame.copy_from_nonoverlapping(registers.as_ptr(), registers.len());
+
+    frame as u64
+}
+
+#[no_mangle]
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    (*entry)();
+    scheduler::exit()
+}
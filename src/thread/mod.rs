@@ -0,0 +1,139 @@
+mod context;
+mod scheduler;
+
+pub(crate) use scheduler::{probe_stack, running, tick};
+pub use scheduler::{MAX_THREADS, STACK_SIZE};
+
+use crate::{sync::Mutex, time};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Turns the current context into the boot thread and starts preempting on the timer
+pub fn init() {
+    scheduler::init();
+}
+
+/// Starts a kernel thread running `f`
+///
+/// Panics if there are already `MAX_THREADS` threads.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let id = scheduler::spawn(Box::new(move || {
+        let val = f();
+        *packet.lock() = Some(val);
+    }));
+
+    JoinHandle { id, result }
+}
+
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+/// Gives the rest of the time slice to the next ready thread
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// Blocks the current thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    let ticks = (duration.as_nanos() * time::TIMER_HZ as u128 + 999_999_999) / 1_000_000_000;
+    scheduler::sleep_until(time::ticks() + ticks as u64);
+}
+
+/// Ends the current thread, its `JoinHandle` will never produce a value
+pub fn exit() -> ! {
+    scheduler::exit()
+}
+
+/// Dropping the handle detaches the thread
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Panics if the thread called `exit` instead of returning
+    pub fn join(self) -> T {
+        scheduler::join(self.id);
+        let val = self.result.lock().take();
+        val.expect("joined thread exited without a result")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::{self, Instant};
+    use alloc::{sync::Arc, vec::Vec};
+    use core::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test_case]
+    fn join() {
+        let handles: Vec<_> = (0..4).map(|i| super::spawn(move || i * 2)).collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join()).collect();
+        assert_eq!(results, [0, 2, 4, 6]);
+    }
+
+    #[test_case]
+    fn preemption() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters: Vec<_> = (0..2).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+
+        // Neither thread yields, so both only make progress if they get preempted
+        let handles: Vec<_> = counters
+            .iter()
+            .map(|counter| {
+                let (stop, counter) = (stop.clone(), counter.clone());
+                super::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        time::sleep(Duration::from_millis(50));
+        stop.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join();
+        }
+        assert!(counters.iter().all(|c| c.load(Ordering::SeqCst) > 0));
+    }
+
+    #[test_case]
+    fn sleep() {
+        let start = Instant::now();
+        let handle = super::spawn(move || super::sleep(Duration::from_millis(20)));
+        handle.join();
+        assert!(start.elapsed() >= Duration::from_millis(19));
+    }
+}
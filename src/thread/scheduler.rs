@@ -0,0 +1,337 @@
+use super::{
+    context::{self, Entry},
+    ThreadId,
+};
+use crate::{
+    mem::stack::Stack,
+    sync::{mutex::MutexGuard, Mutex},
+    time,
+};
+use alloc::boxed::Box;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageSize, Size4KiB},
+};
+
+pub const MAX_THREADS: usize = 64;
+pub const STACK_SIZE: usize = 256 * 1024;
+// Mapped up front, the rest is faulted in when first touched. That works from interrupt
+// handlers too since the page table locks can't be held by a preempted thread, and
+// their holders never fault on the stack since locking them probes it first.
+const COMMITTED_STACK: usize = 16 * 1024;
+// How far below the stack pointer `probe_stack` faults in pages
+const STACK_PROBE: u64 = 16 * 1024;
+
+// Ticks a thread runs for before it is preempted
+const TIME_SLICE: u64 = 10;
+
+// Only locked with interrupts disabled, and never allocates or frees while locked since a
+// preempted thread could be holding the allocator lock
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+// Id of the running thread, for exception handlers which can't take the scheduler lock.
+// The boot thread gets the first id.
+static RUNNING: AtomicU64 = AtomicU64::new(0);
+// Bottom of the running thread's stack, 0 for the boot thread whose stack is all mapped
+static STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    Sleeping(u64),
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    rsp: u64,
+    state: State,
+    // The boot thread runs on the stack the bootloader set up
+    stack: Option<Stack>,
+}
+
+impl Thread {
+    fn new(entry: Entry) -> Box<Self> {
+        let stack =
+            Stack::reserve(STACK_SIZE, COMMITTED_STACK).expect("thread stack allocation failed");
+
+        Box::new(Self {
+            id: ThreadId::new(),
+            rsp: unsafe { context::init_stack(stack.top().as_u64(), entry) },
+            state: State::Ready,
+            stack: Some(stack),
+        })
+    }
+
+    fn stack_bottom(&self) -> u64 {
+        self.stack
+            .as_ref()
+            .map_or(0, |stack| stack.bottom().as_u64())
+    }
+}
+
+struct Scheduler {
+    threads: [Option<Box<Thread>>; MAX_THREADS],
+    // Ring buffer of slots, every thread is in it at most once so it never overflows
+    ready: [usize; MAX_THREADS],
+    ready_head: usize,
+    ready_len: usize,
+    current: usize,
+    idle: usize,
+    slice: u64,
+    started: bool,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            threads: [None; MAX_THREADS],
+            ready: [0; MAX_THREADS],
+            ready_head: 0,
+            ready_len: 0,
+            current: 0,
+            idle: 0,
+            slice: TIME_SLICE,
+            started: false,
+        }
+    }
+
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("empty thread slot")
+    }
+    fn find(&self, id: ThreadId) -> Option<usize> {
+        self.threads
+            .iter()
+            .position(|t| t.as_ref().map_or(false, |t| t.id == id))
+    }
+
+    fn add(&mut self, thread: Box<Thread>) -> Result<ThreadId, Box<Thread>> {
+        let slot = match self.threads.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => return Err(thread),
+        };
+        let id = thread.id;
+        self.threads[slot] = Some(thread);
+        self.push_ready(slot);
+        Ok(id)
+    }
+
+    fn push_ready(&mut self, slot: usize) {
+        self.thread(slot).state = State::Ready;
+        self.ready[(self.ready_head + self.ready_len) % MAX_THREADS] = slot;
+        self.ready_len += 1;
+    }
+    fn pop_ready(&mut self) -> Option<usize> {
+        if self.ready_len == 0 {
+            return None;
+        }
+        let slot = self.ready[self.ready_head];
+        self.ready_head = (self.ready_head + 1) % MAX_THREADS;
+        self.ready_len -= 1;
+        Some(slot)
+    }
+
+    fn wake_where(&mut self, pred: impl Fn(State) -> bool) {
+        for slot in 0..MAX_THREADS {
+            if self.threads[slot].as_ref().map_or(false, |t| pred(t.state)) {
+                self.push_ready(slot);
+            }
+        }
+    }
+}
+
+/// Switches to the next ready thread, putting the current one back in the queue if it is
+/// still running, and returns once the current thread is scheduled again
+fn reschedule(mut sched: MutexGuard<Scheduler>) {
+    let current = sched.current;
+    if sched.thread(current).state == State::Running {
+        if sched.ready_len == 0 {
+            sched.slice = TIME_SLICE;
+            return;
+        }
+        if current == sched.idle {
+            sched.thread(current).state = State::Ready;
+        } else {
+            sched.push_ready(current);
+        }
+    }
+
+    let next = sched.pop_ready().unwrap_or(sched.idle);
+    sched.thread(next).state = State::Running;
+    sched.current = next;
+    RUNNING.store(sched.thread(next).id.0, Ordering::SeqCst);
+    STACK_BOTTOM.store(sched.thread(next).stack_bottom(), Ordering::SeqCst);
+    sched.slice = TIME_SLICE;
+    if next == current {
+        return;
+    }
+
+    // The threads are boxed, so the pointer stays valid after unlocking
+    let old = &mut sched.thread(current).rsp as *mut u64;
+    let new = sched.thread(next).rsp;
+    drop(sched);
+    unsafe { context::switch_context(old, new) };
+}
+
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::new(),
+        rsp: 0,
+        state: State::Running,
+        stack: None,
+    });
+    let idle = Thread::new(Box::new(|| loop {
+        interrupts::enable_and_hlt();
+    }));
+
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        sched.threads[0] = Some(boot);
+        sched.threads[1] = Some(idle);
+        sched.current = 0;
+        sched.idle = 1;
+        sched.started = true;
+        RUNNING.store(sched.thread(0).id.0, Ordering::SeqCst);
+    });
+}
+
+pub fn spawn(entry: Entry) -> ThreadId {
+    reap();
+    let thread = Thread::new(entry);
+
+    let added = interrupts::without_interrupts(|| SCHEDULER.lock().add(thread));
+    match added {
+        Ok(id) => id,
+        Err(thread) => {
+            drop(thread);
+            panic!("more than {} threads", MAX_THREADS)
+        }
+    }
+}
+
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.current;
+        sched.thread(current).id
+    })
+}
+
+/// Like `current` but without taking the scheduler lock, so it works in any context
+pub(crate) fn running() -> ThreadId {
+    ThreadId(RUNNING.load(Ordering::SeqCst))
+}
+
+/// Faults in the running thread's stack pages within `STACK_PROBE` below the stack pointer
+///
+/// For code about to take a lock the page fault handler needs, a fault on the stack
+/// while holding it couldn't be resolved.
+pub(crate) fn probe_stack() {
+    let bottom = STACK_BOTTOM.load(Ordering::SeqCst);
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    // Not on a thread stack, like in an exception handler on its own stack
+    if bottom == 0 || rsp < bottom || rsp - bottom > STACK_SIZE as u64 {
+        return;
+    }
+
+    let mut page = rsp.saturating_sub(STACK_PROBE).max(bottom) & !(Size4KiB::SIZE - 1);
+    while page < rsp {
+        unsafe { ptr::read_volatile(page as *const u8) };
+        page += Size4KiB::SIZE;
+    }
+}
+
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        if sched.started {
+            reschedule(sched);
+        }
+    });
+}
+
+/// Sleeps until the tick count reaches `deadline`
+pub fn sleep_until(deadline: u64) {
+    while time::ticks() < deadline {
+        interrupts::without_interrupts(|| {
+            let mut sched = SCHEDULER.lock();
+            if !sched.started {
+                drop(sched);
+                interrupts::enable_and_hlt();
+                return;
+            }
+            let current = sched.current;
+            sched.thread(current).state = State::Sleeping(deadline);
+            reschedule(sched);
+        });
+    }
+}
+
+/// Blocks until the thread has finished, returning right away if it already has
+pub fn join(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let running = sched
+            .find(id)
+            .map_or(false, |slot| sched.thread(slot).state != State::Finished);
+        if running {
+            let current = sched.current;
+            sched.thread(current).state = State::Joining(id);
+            reschedule(sched);
+        }
+    });
+    reap();
+}
+
+pub fn exit() -> ! {
+    interrupts::disable();
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    let id = sched.thread(current).id;
+    sched.thread(current).state = State::Finished;
+    sched.wake_where(|state| state == State::Joining(id));
+    reschedule(sched);
+
+    unreachable!("finished thread scheduled again")
+}
+
+/// Runs the scheduler from the timer interrupt, after the interrupt has been acknowledged
+pub(crate) fn tick(now: u64) {
+    let mut sched = SCHEDULER.lock();
+    if !sched.started {
+        return;
+    }
+
+    sched.wake_where(|state| matches!(state, State::Sleeping(deadline) if deadline <= now));
+    sched.slice = sched.slice.saturating_sub(1);
+    if sched.slice == 0 || (sched.current == sched.idle && sched.ready_len != 0) {
+        reschedule(sched);
+    }
+}
+
+// Finished threads can't free their own stack, so whoever spawns or joins next does
+fn reap() {
+    let mut dead: [Option<Box<Thread>>; MAX_THREADS] = [None; MAX_THREADS];
+
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.current;
+        for (slot, (thread, dead)) in sched.threads.iter_mut().zip(dead.iter_mut()).enumerate() {
+            if slot != current
+                && thread
+                    .as_ref()
+                    .map_or(false, |t| t.state == State::Finished)
+            {
+                *dead = thread.take();
+            }
+        }
+    });
+
+    drop(dead);
+}
@@ -0,0 +1,264 @@
+use crate::{
+    acpi::{GenericAddress, ACPI},
+    interrupts::apic::{IO_APIC, LOCAL_APIC},
+    mem::{
+        mmio::{CacheMode, Mmio},
+        Volatile,
+    },
+    sync::{Mutex, Once},
+};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use x86_64::{instructions::interrupts, PhysAddr};
+
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+const COUNTER_64BIT: u64 = 1 << 13;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_32BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_FSB: u64 = 1 << 14;
+
+// Longest period the specification allows, 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+pub static HPET: Once<Hpet> = Once::new();
+
+// Comparator 0 drives one-shot interrupts once it is routed
+static ROUTED: AtomicBool = AtomicBool::new(false);
+static CALLBACK: Mutex<Option<fn()>> = Mutex::new(None);
+
+/// Maps and starts the HPET described by ACPI, leaving `HPET` uninitialised if there is none
+pub fn init() {
+    let table = match ACPI.try_get().and_then(|acpi| acpi.hpet) {
+        Some(table)
+            if table.address.address_space == GenericAddress::SYSTEM_MEMORY
+                && table.address.address != 0 =>
+        {
+            table
+        }
+        _ => return,
+    };
+    let regs = match unsafe { Mmio::map(PhysAddr::new(table.address.address), CacheMode::Uncached) }
+    {
+        Ok(regs) => regs,
+        Err(_) => return,
+    };
+
+    let mut hpet = Hpet {
+        regs,
+        period: 0,
+        comparators: 0,
+        counter_64bit: false,
+        minimum_tick: table.minimum_tick as u64,
+    };
+    let capabilities = hpet.regs.capabilities.read();
+    hpet.period = capabilities >> 32;
+    // Only as many comparators as fit in the register block
+    hpet.comparators = (((capabilities >> 8) & 0b1_1111) as u8 + 1).min(MAX_COMPARATORS as u8);
+    hpet.counter_64bit = capabilities & COUNTER_64BIT != 0;
+    if hpet.period == 0 || hpet.period > MAX_PERIOD_FS {
+        return;
+    }
+
+    let regs = &hpet.regs;
+    let config = regs.config.read() & !(ENABLE | LEGACY_REPLACEMENT);
+    regs.config.write(config);
+    regs.main_counter.write(0);
+    for timer in &regs.timers[..hpet.comparators as usize] {
+        timer
+            .config
+            .write(timer.config.read() & !(TIMER_ENABLE | TIMER_FSB));
+    }
+    regs.config.write(config | ENABLE);
+
+    HPET.init_once(|| hpet);
+}
+
+/// Nanoseconds since the HPET was started, if it has a counter that doesn't wrap
+pub fn nanos() -> Option<u64> {
+    let hpet = HPET.try_get().filter(|hpet| hpet.counter_64bit)?;
+    Some(hpet.to_nanos(hpet.counter()))
+}
+
+/// Routes comparator 0 through the I/O APIC so `oneshot` can be used
+///
+/// # Safety
+/// `vector` has to have a handler which calls `interrupt`
+pub unsafe fn enable_interrupt(vector: u8) {
+    let (hpet, io_apic, local) = match (HPET.try_get(), IO_APIC.try_get(), LOCAL_APIC.try_get()) {
+        (Some(hpet), Some(io_apic), Some(local)) => (hpet, io_apic, local),
+        _ => return,
+    };
+
+    // Prefer inputs above the ISA range so nothing else is wired there
+    let timer = &hpet.regs.timers[0];
+    let mut io_apic = io_apic.lock();
+    let allowed =
+        (timer.config.read() >> 32) as u32 & (u32::MAX >> (32 - io_apic.entries().min(32) as u32));
+    if allowed == 0 {
+        return;
+    }
+    let gsi = match allowed & !0xFFFF {
+        0 => allowed.trailing_zeros() as u8,
+        above => above.trailing_zeros() as u8,
+    };
+
+    let config = timer.config.read()
+        & !(TIMER_LEVEL_TRIGGERED
+            | TIMER_ENABLE
+            | TIMER_PERIODIC
+            | TIMER_32BIT
+            | TIMER_FSB
+            | 0b1_1111 << TIMER_ROUTE_SHIFT);
+    timer
+        .config
+        .write(config | (gsi as u64) << TIMER_ROUTE_SHIFT);
+    io_apic.route(gsi, vector, local.id());
+    ROUTED.store(true, Ordering::SeqCst);
+}
+
+/// Calls `callback` from the interrupt handler once `delay` has passed, replacing
+/// any pending one-shot
+///
+/// Returns `false` if there is no HPET or its interrupt couldn't be routed.
+pub fn oneshot(delay: Duration, callback: fn()) -> bool {
+    let hpet = match HPET.try_get() {
+        Some(hpet) if ROUTED.load(Ordering::SeqCst) => hpet,
+        _ => return false,
+    };
+
+    interrupts::without_interrupts(|| {
+        *CALLBACK.lock() = Some(callback);
+
+        // The comparator only fires when the counter passes it, so it can't be too close
+        let ticks = hpet
+            .ticks_in(delay.as_nanos() as u64)
+            .max(hpet.minimum_tick)
+            .max(1);
+        let timer = &hpet.regs.timers[0];
+        timer.config.write(timer.config.read() | TIMER_ENABLE);
+        timer.comparator.write(hpet.counter().wrapping_add(ticks));
+    });
+
+    true
+}
+
+/// Handles the comparator 0 interrupt
+pub(crate) fn interrupt() {
+    if let Some(hpet) = HPET.try_get() {
+        let timer = &hpet.regs.timers[0];
+        timer.config.write(timer.config.read() & !TIMER_ENABLE);
+    }
+    let callback = CALLBACK.lock().take();
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+// The register block is 1 KiB, which leaves room for 24 of the up to 32 comparators
+const MAX_COMPARATORS: usize = 24;
+
+#[repr(C)]
+struct Registers {
+    capabilities: Volatile<u64>,
+    _reserved0: u64,
+    config: Volatile<u64>,
+    _reserved1: [u64; 27],
+    main_counter: Volatile<u64>,
+    _reserved2: u64,
+    timers: [Timer; MAX_COMPARATORS],
+}
+
+#[repr(C)]
+struct Timer {
+    config: Volatile<u64>,
+    comparator: Volatile<u64>,
+    _reserved: [u64; 2],
+}
+
+pub struct Hpet {
+    regs: Mmio<Registers>,
+    // Femtoseconds per counter tick
+    period: u64,
+    comparators: u8,
+    counter_64bit: bool,
+    minimum_tick: u64,
+}
+
+impl Hpet {
+    pub fn counter(&self) -> u64 {
+        self.regs.main_counter.read()
+    }
+
+    /// Counter ticks per second
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+    pub fn counter_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    pub fn to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period as u128 / 1_000_000) as u64
+    }
+    pub fn ticks_in(&self, nanos: u64) -> u64 {
+        (nanos as u128 * 1_000_000 / self.period as u128) as u64
+    }
+
+    /// Spins for the given duration, handling a 32-bit counter wrapping
+    pub fn busy_wait(&self, micros: u64) {
+        let mask = if self.counter_64bit {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        let ticks = self.ticks_in(micros * 1000);
+        let start = self.counter();
+        while self.counter().wrapping_sub(start) & mask < ticks {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registers, HPET};
+    use core::{
+        mem,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    #[test_case]
+    fn register_layout() {
+        assert_eq!(mem::size_of::<Registers>(), 0x400);
+    }
+
+    #[test_case]
+    fn counter() {
+        if let Some(hpet) = HPET.try_get().filter(|hpet| hpet.counter_64bit()) {
+            let start = hpet.counter();
+            hpet.busy_wait(1000);
+            assert!(hpet.to_nanos(hpet.counter() - start) >= 1_000_000);
+        }
+    }
+
+    #[test_case]
+    fn oneshot() {
+        static FIRED: AtomicBool = AtomicBool::new(false);
+
+        if super::oneshot(Duration::from_millis(5), || {
+            FIRED.store(true, Ordering::SeqCst)
+        }) {
+            crate::time::sleep(Duration::from_millis(20));
+            assert!(FIRED.load(Ordering::SeqCst));
+        }
+    }
+}
@@ -1,3 +1,140 @@
-use core::sync::atomic::AtomicUsize;
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+mod system;
+pub mod timer;
+pub mod tsc;
+
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+pub use system::{DateTime, SystemTime, UNIX_EPOCH};
+pub use timer::{sleep, sleep_until};
+
+/// Rate at which the timer interrupt increments `TICKS`
+pub const TIMER_HZ: u64 = 1000;
 
 pub static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    hpet::init();
+    tsc::init();
+    system::init();
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed) as u64
+}
+
+pub fn uptime() -> Duration {
+    Instant::now().0
+}
+
+/// Point in time since boot which never goes backwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// Uses the TSC when it is reliable, then the HPET, otherwise the timer tick count
+    pub fn now() -> Self {
+        match (tsc::nanos(), hpet::nanos()) {
+            (Some(nanos), _) if tsc::invariant() => Self(Duration::from_nanos(nanos)),
+            (_, Some(nanos)) => Self(Duration::from_nanos(nanos)),
+            _ => Self::from_ticks(ticks()),
+        }
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        let secs = ticks / TIMER_HZ;
+        let nanos = (ticks % TIMER_HZ) * 1_000_000_000 / TIMER_HZ;
+        Self(Duration::new(secs, nanos as u32))
+    }
+
+    pub fn since_boot(&self) -> Duration {
+        self.0
+    }
+
+    /// Saturates to zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Instant)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+// Formats as `[    12.345678]`, like kernel log timestamps
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:5}.{:06}]", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instant;
+    use core::time::Duration;
+
+    #[test_case]
+    fn monotonic() {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {
+            x86_64::instructions::hlt();
+        }
+        let end = Instant::now();
+        assert!(end > start);
+        assert!(end - start >= Duration::from_millis(20));
+        assert_eq!(start - end, Duration::from_secs(0));
+    }
+}
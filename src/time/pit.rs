@@ -0,0 +1,47 @@
+use x86_64::instructions::port::Port;
+
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const GATE: u16 = 0x61;
+
+/// Makes channel 0, wired to IRQ 0, fire `hz` times per second
+pub fn set_frequency(hz: u64) {
+    let divisor = (FREQUENCY / hz).min(0xFFFF).max(1) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel0: Port<u8> = Port::new(CHANNEL0);
+    unsafe {
+        // Channel 0, lobyte/hibyte, mode 2 (rate generator)
+        command.write(0b0011_0100);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Spins for the given duration using channel 2, which isn't wired to an interrupt
+///
+/// Durations are limited to about 54 ms by the 16-bit counter.
+pub fn busy_wait(micros: u64) {
+    let count = (FREQUENCY * micros / 1_000_000).min(0xFFFF).max(1) as u16;
+
+    let mut gate: Port<u8> = Port::new(GATE);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel2: Port<u8> = Port::new(CHANNEL2);
+    unsafe {
+        // Gate low and speaker off while programming
+        let state = gate.read() & !0b11;
+        gate.write(state);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        gate.write(state | 0b01);
+        while gate.read() & 0b0010_0000 == 0 {}
+        gate.write(state);
+    }
+}
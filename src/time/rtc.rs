@@ -0,0 +1,177 @@
+use super::DateTime;
+use crate::acpi::ACPI;
+use x86_64::instructions::{interrupts, port::Port};
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_D: u8 = 0x0D;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// Reads the current date and time, assumed to be UTC
+pub fn read() -> DateTime {
+    let century_register = ACPI
+        .try_get()
+        .and_then(|acpi| acpi.fadt)
+        .map_or(0, |fadt| fadt.century);
+
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        // The registers can change between reads, so read until two agree
+        let mut last = read_raw(century_register);
+        loop {
+            let raw = read_raw(century_register);
+            if raw == last {
+                break (raw, read_register(STATUS_B));
+            }
+            last = raw;
+        }
+    });
+
+    decode(raw, status_b, century_register != 0)
+}
+
+// Converts the registers from BCD and 12 hour time if status B says they are
+fn decode(raw: Raw, status_b: u8, has_century: bool) -> DateTime {
+    let decode = |val: u8| {
+        if status_b & BINARY != 0 {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0x0F)
+        }
+    };
+
+    let mut hour = decode(raw.hours & !PM);
+    if status_b & HOURS_24 == 0 {
+        let pm = raw.hours & PM != 0;
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = if has_century {
+        decode(raw.century) as u16
+    } else {
+        20
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minutes),
+        second: decode(raw.seconds),
+    }
+}
+
+fn read_raw(century_register: u8) -> Raw {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+
+    Raw {
+        seconds: read_register(SECONDS),
+        minutes: read_register(MINUTES),
+        hours: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: match century_register {
+            0 => 0,
+            reg => read_register(reg),
+        },
+    }
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut select: Port<u8> = Port::new(0x70);
+    let mut data: Port<u8> = Port::new(0x71);
+    unsafe {
+        // Keep NMIs disabled while selecting
+        select.write(reg | 0x80);
+        let val = data.read();
+        // Bit 7 of the index is the NMI mask, so it's cleared again on a harmless register
+        select.write(STATUS_D);
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Raw, BINARY, HOURS_24, PM};
+    use crate::time::DateTime;
+
+    fn raw(hours: u8) -> Raw {
+        Raw {
+            seconds: 0x59,
+            minutes: 0x07,
+            hours,
+            day: 0x31,
+            month: 0x12,
+            year: 0x99,
+            century: 0x19,
+        }
+    }
+
+    #[test_case]
+    fn bcd() {
+        assert_eq!(
+            super::decode(raw(0x23), HOURS_24, true),
+            DateTime {
+                year: 1999,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 7,
+                second: 59,
+            }
+        );
+        // Without a century register the 21st century is assumed
+        assert_eq!(super::decode(raw(0x23), HOURS_24, false).year, 2099);
+
+        let binary = Raw {
+            seconds: 59,
+            minutes: 7,
+            hours: 23,
+            day: 31,
+            month: 12,
+            year: 99,
+            century: 19,
+        };
+        assert_eq!(
+            super::decode(binary, HOURS_24 | BINARY, true),
+            super::decode(raw(0x23), HOURS_24, true)
+        );
+    }
+
+    #[test_case]
+    fn twelve_hour() {
+        let hour = |hours, status_b| super::decode(raw(hours), status_b, true).hour;
+        // 12 AM is midnight and 12 PM is noon
+        assert_eq!(hour(0x12, 0), 0);
+        assert_eq!(hour(0x12 | PM, 0), 12);
+        assert_eq!(hour(0x01, 0), 1);
+        assert_eq!(hour(0x11 | PM, 0), 23);
+        assert_eq!(hour(12 | PM, BINARY), 12);
+        assert_eq!(hour(11 | PM, BINARY), 23);
+    }
+}
@@ -0,0 +1,140 @@
+use super::{rtc, Instant};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Wall clock time at boot in nanoseconds since the epoch
+static BOOT: AtomicU64 = AtomicU64::new(0);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+pub fn init() {
+    let now = rtc::read().to_unix();
+    let boot = Duration::from_secs(now)
+        .checked_sub(Instant::now().since_boot())
+        .unwrap_or_default();
+    BOOT.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Wall clock time, read from the RTC at boot and advanced by the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    pub fn now() -> Self {
+        let boot = Duration::from_nanos(BOOT.load(Ordering::Relaxed));
+        Self(boot + Instant::now().since_boot())
+    }
+
+    pub fn from_unix(secs: u64) -> Self {
+        Self(Duration::from_secs(secs))
+    }
+
+    /// Returns `None` if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+    pub fn since_epoch(&self) -> Duration {
+        self.0
+    }
+
+    pub fn to_datetime(&self) -> DateTime {
+        DateTime::from_unix(self.0.as_secs())
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_datetime().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+            as u64
+    }
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64 + 719_468;
+        let secs = secs % 86400;
+
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+// ISO 8601
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DateTime, SystemTime};
+
+    #[test_case]
+    fn unix_roundtrip() {
+        let date = DateTime {
+            year: 2020,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(date.to_unix(), 1_582_983_462);
+        assert_eq!(DateTime::from_unix(1_582_983_462), date);
+        assert_eq!(DateTime::from_unix(0).year, 1970);
+    }
+
+    #[test_case]
+    fn now() {
+        assert!(SystemTime::now().to_datetime().year >= 2020);
+    }
+}
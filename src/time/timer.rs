@@ -0,0 +1,230 @@
+use super::{ticks, Instant, TIMER_HZ};
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+const SLOTS: usize = 256;
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Entry {
+    id: u64,
+    deadline: u64,
+    period: u64,
+    callback: Box<dyn FnMut() + Send>,
+    next: Option<Box<Entry>>,
+}
+
+// Hashed timing wheel, entries live in the slot of their deadline tick
+// and are skipped until the wheel has gone around enough times
+struct Wheel {
+    slots: [Option<Box<Entry>>; SLOTS],
+    next_id: u64,
+    firing: Option<u64>,
+    firing_cancelled: bool,
+    // Fired entries can't be freed from the interrupt handler since
+    // the code it interrupted might hold the allocator lock
+    dead: Option<Box<Entry>>,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            slots: [None; SLOTS],
+            next_id: 0,
+            firing: None,
+            firing_cancelled: false,
+            dead: None,
+        }
+    }
+
+    fn insert(&mut self, mut entry: Box<Entry>) {
+        let slot = &mut self.slots[entry.deadline as usize % SLOTS];
+        entry.next = slot.take();
+        *slot = Some(entry);
+    }
+
+    fn bury(&mut self, mut entry: Box<Entry>) {
+        entry.next = self.dead.take();
+        self.dead = Some(entry);
+    }
+}
+
+fn unlink(list: &mut Option<Box<Entry>>, pred: impl Fn(&Entry) -> bool) -> Option<Box<Entry>> {
+    let mut cursor = list;
+    while cursor.as_ref().map_or(false, |e| !pred(e)) {
+        cursor = &mut cursor.as_mut().unwrap().next;
+    }
+    let mut entry = cursor.take()?;
+    *cursor = entry.next.take();
+    Some(entry)
+}
+
+fn to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * TIMER_HZ as u128 + 999_999_999) / 1_000_000_000;
+    (ticks as u64).max(1)
+}
+
+fn schedule(delay: Duration, period: u64, callback: Box<dyn FnMut() + Send>) -> TimerId {
+    let mut entry = Box::new(Entry {
+        id: 0,
+        deadline: 0,
+        period,
+        callback,
+        next: None,
+    });
+
+    let (id, dead) = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let id = wheel.next_id;
+        wheel.next_id += 1;
+
+        entry.id = id;
+        entry.deadline = ticks() + to_ticks(delay);
+        wheel.insert(entry);
+        (id, wheel.dead.take())
+    });
+    drop(dead);
+
+    TimerId(id)
+}
+
+/// Runs `callback` once after `delay`
+///
+/// Callbacks run in the timer interrupt handler, so they can't allocate or take
+/// locks which might be held by the code they interrupted.
+pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    schedule(delay, 0, Box::new(callback))
+}
+
+/// Runs `callback` every `period`, starting one period from now
+///
+/// The same restrictions as for `after` apply to the callback.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    schedule(period, to_ticks(period), Box::new(callback))
+}
+
+/// Returns whether the timer was still pending
+pub fn cancel(id: TimerId) -> bool {
+    let (found, entry, dead) = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let dead = wheel.dead.take();
+
+        if wheel.firing == Some(id.0) {
+            wheel.firing_cancelled = true;
+            return (true, None, dead);
+        }
+        let entry = wheel
+            .slots
+            .iter_mut()
+            .find_map(|slot| unlink(slot, |e| e.id == id.0));
+        (entry.is_some(), entry, dead)
+    });
+    drop(entry);
+    drop(dead);
+
+    found
+}
+
+/// Fires the timers due at tick `now`
+pub(crate) fn tick(now: u64) {
+    loop {
+        let mut entry = {
+            let mut wheel = WHEEL.lock();
+            match unlink(&mut wheel.slots[now as usize % SLOTS], |e| {
+                e.deadline <= now
+            }) {
+                Some(entry) => {
+                    wheel.firing = Some(entry.id);
+                    wheel.firing_cancelled = false;
+                    entry
+                }
+                None => break,
+            }
+        };
+
+        (entry.callback)();
+
+        let mut wheel = WHEEL.lock();
+        wheel.firing = None;
+        if entry.period != 0 && !wheel.firing_cancelled {
+            entry.deadline = now + entry.period;
+            wheel.insert(entry);
+        } else {
+            wheel.bury(entry);
+        }
+    }
+}
+
+/// Halts the CPU until `duration` has elapsed
+///
+/// Interrupts are enabled while halted, since the timer has to tick, and are disabled
+/// again afterwards if they were on entry.
+pub fn sleep(duration: Duration) {
+    let deadline = ticks() + to_ticks(duration);
+    let enabled = interrupts::are_enabled();
+    while ticks() < deadline {
+        interrupts::enable_and_hlt();
+    }
+    if !enabled {
+        interrupts::disable();
+    }
+}
+
+/// Halts the CPU until `deadline` has passed
+pub fn sleep_until(deadline: Instant) {
+    sleep(deadline.duration_since(Instant::now()));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::Instant;
+    use alloc::sync::Arc;
+    use core::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test_case]
+    fn sleep() {
+        let start = Instant::now();
+        super::sleep(Duration::from_millis(30));
+        assert!(start.elapsed() >= Duration::from_millis(29));
+    }
+
+    #[test_case]
+    fn sleep_keeps_interrupts_disabled() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            super::sleep(Duration::from_millis(5));
+            assert!(!x86_64::instructions::interrupts::are_enabled());
+        });
+    }
+
+    #[test_case]
+    fn callbacks() {
+        let once = Arc::new(AtomicUsize::new(0));
+        let periodic = Arc::new(AtomicUsize::new(0));
+
+        let counter = once.clone();
+        super::after(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let counter = periodic.clone();
+        let id = super::every(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        super::sleep(Duration::from_millis(50));
+        assert!(super::cancel(id));
+        let fired = periodic.load(Ordering::SeqCst);
+        super::sleep(Duration::from_millis(20));
+
+        assert_eq!(once.load(Ordering::SeqCst), 1);
+        assert!(fired >= 5);
+        assert_eq!(periodic.load(Ordering::SeqCst), fired);
+    }
+}
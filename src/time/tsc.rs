@@ -0,0 +1,68 @@
+use super::{hpet::HPET, pit};
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static ORIGIN: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC runs at a constant rate regardless of power states
+pub fn invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Ticks per second, if calibrated
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Measures the TSC frequency against a known delay, taking the shortest
+/// of a few runs since interference can only make a run longer
+pub fn calibrate(wait: impl Fn(u64)) {
+    const RUNS: usize = 3;
+    const MICROS: u64 = 10_000;
+
+    let ticks = (0..RUNS)
+        .map(|_| {
+            let start = read();
+            wait(MICROS);
+            read() - start
+        })
+        .min()
+        .unwrap();
+
+    ORIGIN.store(read(), Ordering::Relaxed);
+    FREQUENCY.store(ticks * 1_000_000 / MICROS, Ordering::Relaxed);
+}
+
+pub fn init() {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    let invariant =
+        max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0;
+    INVARIANT.store(invariant, Ordering::Relaxed);
+
+    match HPET.try_get() {
+        Some(hpet) => calibrate(|micros| hpet.busy_wait(micros)),
+        None => calibrate(pit::busy_wait),
+    }
+}
+
+/// Nanoseconds since calibration, or `None` before it
+pub fn nanos() -> Option<u64> {
+    let hz = frequency()?;
+    let ticks = read() - ORIGIN.load(Ordering::Relaxed);
+    Some(to_nanos(ticks, hz))
+}
+
+pub fn to_nanos(ticks: u64, hz: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / hz as u128) as u64
+}
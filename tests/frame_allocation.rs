@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(obamas::test::runner)]
+#![reexport_test_harness_main = "_test"]
+
+use bootloader::BootInfo;
+use obamas::{
+    mem::frame::{FrameAlloc, FRAMES},
+    sync::mutex::IrqMutexGuard,
+};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB};
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    obamas::mem::init(boot_info);
+
+    _test();
+
+    obamas::halt();
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    obamas::test::panic_handler(info)
+}
+
+fn frames() -> IrqMutexGuard<'static, FrameAlloc> {
+    FRAMES.try_get().unwrap().lock()
+}
+
+#[test_case]
+fn reuse() {
+    let mut frames = frames();
+    let before = frames.stats();
+
+    let frame = frames.allocate().unwrap();
+    assert_eq!(frames.stats().used, before.used + 1);
+    unsafe { frames.deallocate(frame) };
+    assert_eq!(frames.stats(), before);
+
+    assert_eq!(frames.allocate(), Some(frame));
+    unsafe { frames.deallocate(frame) };
+}
+
+#[test_case]
+fn distinct() {
+    let mut frames = frames();
+    let a = frames.allocate().unwrap();
+    let b = frames.allocate().unwrap();
+    assert_ne!(a, b);
+    unsafe {
+        frames.deallocate(a);
+        frames.deallocate(b);
+    }
+}
+
+#[test_case]
+fn contiguous() {
+    let mut frames = frames();
+    let before = frames.stats();
+
+    let start = frames.allocate_contiguous(16, 4).unwrap();
+    assert_eq!(start.start_address().as_u64() % (4 * 4096), 0);
+    assert_eq!(frames.stats().used, before.used + 16);
+
+    let single = frames.allocate().unwrap();
+    assert!(PhysFrame::range(start, start + 16).all(|f| f != single));
+
+    unsafe {
+        frames.deallocate(single);
+        frames.deallocate_contiguous(start, 16);
+    }
+    assert_eq!(frames.stats(), before);
+}
+
+#[test_case]
+fn huge() {
+    let mut frames = frames();
+    let before = frames.stats();
+
+    let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+    assert_eq!(frame.start_address().as_u64() % Size2MiB::SIZE, 0);
+    assert_eq!(frames.stats().used, before.used + 512);
+
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(frames.stats(), before);
+}
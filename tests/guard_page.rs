@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::BootInfo;
+use obamas::{
+    mem::{
+        stack::{self, Stack},
+        Volatile,
+    },
+    qemu, s1print, s1println,
+    sync::Lazy,
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(test_page_fault_handler)
+            .set_stack_index(obamas::gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt
+});
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _: &mut InterruptStackFrame,
+    err: PageFaultErrorCode,
+) {
+    if err.contains(PageFaultErrorCode::PROTECTION_VIOLATION) || !stack::is_guard(Cr2::read()) {
+        panic!("fault outside of the guard page");
+    }
+    s1println!("ok");
+    qemu::exit(qemu::ExitCode::Success);
+    obamas::halt();
+}
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    s1print!("{} ... ", module_path!());
+
+    obamas::mem::init(boot_info);
+    obamas::gdt::init();
+    TEST_IDT.load();
+
+    let stack = Stack::allocate(16 * 1024).unwrap();
+    let mapped = (stack.bottom().as_u64()..stack.top().as_u64()).step_by(4096);
+    for addr in mapped {
+        unsafe { (*(addr as *mut Volatile<u8>)).write(0) };
+    }
+    // One byte below the stack is in the guard page
+    unsafe { (*((stack.bottom().as_u64() - 1) as *mut Volatile<u8>)).write(0) };
+
+    panic!("Execution after writing to the guard page")
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    obamas::test::panic_handler(info)
+}
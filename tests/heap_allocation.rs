@@ -6,19 +6,13 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::BootInfo;
-use x86_64::VirtAddr;
 
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
-    let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { obamas::mem::paging::mapper(phys_offset) };
-    let mut frame_allocator =
-        unsafe { obamas::mem::paging::frame_allocator(&boot_info.memory_map) };
-
-    obamas::mem::alloc::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    // Heap growth is demand paged, which needs the page fault handler
+    obamas::init(boot_info);
 
     _test();
 
@@ -65,3 +59,82 @@ fn fill_long_lived() {
     }
     assert_eq!(*long, 2112);
 }
+
+#[test_case]
+fn grow() {
+    let n = 4 * obamas::mem::alloc::HEAP_SIZE;
+    let vec = vec![1u8; n];
+    assert_eq!(vec.iter().map(|&b| b as usize).sum::<usize>(), n);
+    assert!(obamas::mem::alloc::size() > obamas::mem::alloc::HEAP_SIZE);
+}
+
+// Padding puts the boxes in a bigger size class with heap-debug
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn slabs_released() {
+    let before = obamas::mem::alloc::size_classes()[0];
+
+    let boxes: Vec<Box<u8>> = (0..10_000).map(|i| Box::new(i as u8)).collect();
+    let during = obamas::mem::alloc::size_classes()[0];
+    assert!(during.slabs > before.slabs);
+    assert_eq!(during.used, before.used + boxes.len());
+    drop(boxes);
+
+    let after = obamas::mem::alloc::size_classes()[0];
+    assert_eq!(after.used, before.used);
+    assert!(after.slabs <= before.slabs.max(1));
+}
+
+#[test_case]
+fn stats() {
+    let before = obamas::mem::alloc::stats();
+    {
+        let vec: Vec<u64> = (0..512).collect();
+        let boxed = Box::new([0u8; 4096]);
+        assert_eq!(vec.len() + boxed.len(), 512 + 4096);
+        let during = obamas::mem::alloc::stats();
+        assert_eq!(during.live, before.live + 2);
+        assert_eq!(during.used, before.used + 512 * 8 + 4096);
+    }
+    let after = obamas::mem::alloc::stats();
+    assert_eq!(after.live, before.live);
+    assert_eq!(after.used, before.used);
+    assert!(after.peak >= before.used + 512 * 8 + 4096);
+    assert_eq!(after.allocations, before.allocations + 2);
+}
+
+#[cfg(feature = "heap-trace")]
+#[test_case]
+fn trace() {
+    let checkpoint = obamas::mem::alloc::checkpoint();
+    let leaked = Box::leak(Box::new(42u32));
+    let kept = Box::new(7u64);
+
+    let leaks = obamas::mem::alloc::leaks_since(checkpoint);
+    assert_eq!(leaks.len(), 2);
+    assert_eq!(leaks[0].addr, leaked as *mut u32 as usize);
+    assert!(leaks[0].backtrace.iter().filter(|&&ret| ret != 0).count() > 1);
+    assert_eq!(leaks[1].layout.size(), 8);
+    drop(kept);
+    drop(leaks);
+
+    let leaks = obamas::mem::alloc::leaks_since(checkpoint);
+    assert_eq!(leaks.len(), 1);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn poison() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    let layout = Layout::new::<[u8; 64]>();
+    unsafe {
+        let ptr = alloc(layout);
+        let bytes = || (0..layout.size()).map(|i| ptr.add(i).read_volatile());
+        // Filled with a pattern instead of whatever the last owner left
+        assert!(bytes().all(|b| b == 0xCD));
+        ptr.write_bytes(0, layout.size());
+        dealloc(ptr, layout);
+        assert!(bytes().all(|b| b == 0xDD));
+    }
+}
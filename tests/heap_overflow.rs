@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::BootInfo;
+use obamas::s1print;
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    s1print!("{} ... ", module_path!());
+
+    obamas::mem::init(boot_info);
+
+    // Too large for a slab, so this goes through the fallback heap
+    let layout = Layout::new::<[u8; 4096]>();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.add(layout.size()).write_volatile(0);
+        dealloc(ptr, layout);
+    }
+
+    panic!("Execution after freeing a buffer written past its end")
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    obamas::test::should_panic_handler(info, "overflow past")
+}
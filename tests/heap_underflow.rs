@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::BootInfo;
+use obamas::s1print;
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    s1print!("{} ... ", module_path!());
+
+    obamas::mem::init(boot_info);
+
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.sub(1).write_volatile(0);
+        dealloc(ptr, layout);
+    }
+
+    panic!("Execution after freeing a buffer written before its start")
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    obamas::test::should_panic_handler(info, "underflow before")
+}
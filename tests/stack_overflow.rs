@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::BootInfo;
 use obamas::{mem::Volatile, qemu, s1print, s1println, sync::Lazy};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -22,9 +23,10 @@ extern "x86-interrupt" fn test_double_fault_handler(_: &mut InterruptStackFrame,
 }
 
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     s1print!("{} ... ", module_path!());
 
+    obamas::mem::init(boot_info);
     obamas::gdt::init();
     TEST_IDT.load();
 
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use obamas::{
+    mem::{vm, Volatile},
+    qemu, s1print, s1println,
+    sync::Lazy,
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::PageTableFlags,
+    },
+};
+
+static PROTECTED: AtomicU64 = AtomicU64::new(0);
+
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(test_page_fault_handler)
+            .set_stack_index(obamas::gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt
+});
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _: &mut InterruptStackFrame,
+    err: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !err.contains(expected) || Cr2::read().as_u64() != PROTECTED.load(Ordering::SeqCst) {
+        panic!("fault outside of the protected page");
+    }
+    s1println!("ok");
+    qemu::exit(qemu::ExitCode::Success);
+    obamas::halt();
+}
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    s1print!("{} ... ", module_path!());
+
+    obamas::mem::init(boot_info);
+    obamas::gdt::init();
+    TEST_IDT.load();
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = vm::allocate_anonymous(4096, flags).unwrap();
+    vm::map(start, 4096).unwrap();
+    let page = start.as_mut_ptr::<Volatile<u8>>();
+    unsafe { (*page).write(1) };
+
+    vm::protect(start, 4096, PageTableFlags::NO_EXECUTE).unwrap();
+    assert_eq!(unsafe { (*page).read() }, 1);
+    PROTECTED.store(start.as_u64(), Ordering::SeqCst);
+    unsafe { (*page).write(2) };
+
+    panic!("Execution after writing to a read-only page")
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    obamas::test::panic_handler(info)
+}